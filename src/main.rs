@@ -1,9 +1,13 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
+#![allow(clippy::needless_return, clippy::print_with_newline, clippy::redundant_field_names, clippy::useless_conversion)]
+#![allow(clippy::needless_late_init, clippy::four_forward_slashes, clippy::single_component_path_imports, clippy::len_zero)]
+#![allow(clippy::needless_range_loop)]
 use std::collections::btree_map::Range;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::collections::VecDeque;
+use std::collections::HashMap;
 use std::vec;
 use std::collections; // Need for BST and Queue
 use rand; 
@@ -13,49 +17,277 @@ use rand_chacha;
 use rand_distr::num_traits::clamp;
 use std::env;
 use std::time;
+use std::io::Write;
 
 
 // Need for RNG and distributions
-use rand_distr::{Distribution, Exp}; // Gives us Exp and Poisson distributions
+use rand_distr::{Distribution, Exp, LogNormal, Normal}; // Gives us Exp and Poisson distributions
 
 // Want for optimized RNG algs
 use rand_chacha::ChaCha8Core;
 use rand_chacha::ChaCha8Rng;
 
 //// HYPERPARAMETRS ////
-const NUMBER_OF_TRAINS : u8 = 50;
+const NUMBER_OF_TRAINS : u8 = 50; // Size of the default fleet (used when no fleet file is given)
 const TRAIN_CAPACITY : f32 = 332.0; // Lowest capcity train (ICTS Mark I w/ four cars)
 const TRAIN_ASSIST_CAPACITY : u8 = 10;
+const TRAIN_CARS : u8 = 4;
+const SEATS_PER_CAR : f32 = 33.0; // Used when a train type doesn't say how many seats it has
+const CRUSH_STANDING_DENSITY : f32 = 6.0; // Standees per m^2 in a train loaded to capacity
+const COMFORTABLE_STANDING_DENSITY : f32 = 2.0; // Standees per m^2 up to which standing is still comfortable
+const CROWDED_STANDING_DENSITY : f32 = 4.0; // Standees per m^2 past which a train counts as crush loaded
+const PLATFORM_BUNCHING : f32 = 0.7; // Share of customers who wait near the entrance they came in by instead of spreading out
+const ENTRANCE_SPREAD : f32 = 0.1; // Standard deviation of how far from their entrance bunched customers wait (in platform lengths)
+const DEFAULT_ENTRANCES : [f32; 3] = [0.15, 0.5, 0.85]; // Entrance positions along the platform (0 = west end, 1 = east end), stations take turns
+const CAR_POSITION_BUCKETS : usize = 4; // How many sections of the train car loading is reported for
+const CROWDING_MULTIPLIERS : [f32; 4] = [1.0, 1.2, 1.6, 2.1]; // How much longer a minute feels seated, standing comfortably, crowded and crush loaded
+const TRAIN_ACCELERATION : f32 = 1.0; // m/s^2, the inter station travel times are measured with this acceleration
+const LINE_SPEED : f32 = 80.0 / 3.6; // m/s, cruising speed between stations
+const PEAK_HEADWAY : f32 = 4.0; // Any hour where TransLink runs trains this often or more is considered peak
+const MAX_CONSIST_CARS : u8 = 6; // Longest train the platforms can fit
+const COUPLING_TIME : f32 = 3.0; // Minutes it takes to couple or uncouple cars at a terminal
+const TRAIN_MTBF : f32 = 3000.0; // Mean minutes in service between failures (used when the fleet file does not give one)
+const TRAIN_MEAN_REPAIR_TIME : f32 = 8.0; // Mean minutes to recover a failed train (used when the fleet file does not give one)
+const REPAIR_TIME_SIGMA : f32 = 0.8; // Shape of the lognormal repair time distribution
+const MAX_REPAIR_IN_PLACE : f32 = 20.0; // Failures that take longer than this to fix get the train pulled out of service
+const RESCUE_TIME : f32 = 15.0; // Minutes it takes to clear a train that is pulled out of service off the line
+const DIVERT_PROBABILITY : f32 = 0.6; // Chance a customer at a closed station goes to a neighbouring station instead of leaving
+const TURNBACK_TIME : f32 = 2.0; // Minutes it takes a short turning train to reverse at a turnback track
+const TRANSFER_WALK_TIME : f32 = 3.0; // Minutes it takes to walk between the platforms of two lines at a shared station
+const INTERLINED_HEADWAY : f32 = 1.5; // Minimum minutes between trains entering track shared by more than one line
+const TRANSFER_PENALTY : f32 = 5.0; // Extra minutes customers count a change of trains as when picking a route
+const ROUTE_ALTERNATIVES : usize = 3; // How many different routes are looked for between each pair of stations when customers pick at random
+const PATIENCE_SIGMA : f32 = 0.5; // Spread of the lognormal patience distribution when customers can give up waiting
+const ROUTE_OVERLAP_PENALTY : f32 = 0.5; // Share of a ride's cost added to it for each route already using it, to find different routes
+const CAR_MASS : f32 = 18.0; // Empty tonnes per car (used when the fleet file does not give a train's mass)
+const PASSENGER_MASS : f32 = 75.0; // kg per customer on board
+const ROLLING_RESISTANCE : f32 = 0.004; // Share of a train's weight needed to keep it moving at line speed (rolling and air resistance together)
+const MOTOR_EFFICIENCY : f32 = 0.85; // Share of the energy drawn from the power rail that ends up moving the train
+const REGEN_RECOVERY : f32 = 0.3; // Share of the braking energy fed back into the power rail
+const AUXILIARY_POWER : f32 = 20.0; // kW per car for lights, heating and ventilation while running
+const GRAVITY : f32 = 9.81; // m/s^2
+const COST_PER_TRAIN_HOUR : f32 = 150.0; // $ per hour a train spends in service (attendants, control centre, power distribution)
+const COST_PER_CAR_KM : f32 = 2.0; // $ of maintenance and wear per car-km
+const COST_PER_KWH : f32 = 0.10; // $ per kWh drawn from the power rail
+const COST_PER_STANDBY_HOUR : f32 = 20.0; // $ per hour a train we own sits out of service (storage, capital)
+const VALUE_OF_WAITING_MINUTE : f32 = 0.40; // $ a customer would pay to wait one minute less
+const VALUE_OF_RIDING_MINUTE : f32 = 0.27; // $ a customer would pay to ride one (crowding-weighted) minute less
+const COST_PER_LOST_TRIP : f32 = 10.0; // $ for each customer who gives up on the trip
+const ANNEAL_STEP : f32 = 0.2; // Standard deviation of each annealing step, as a share of the search range
+const ANNEAL_START_TEMPERATURE : f32 = 0.02; // Share of the starting objective a worse step can cost and still be taken about 1/3 of the time
+const ANNEAL_COOLING : f32 = 0.85; // Temperature is multiplied by this after every annealing step
+const T_QUANTILES : [f32; 30] = [12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160, 2.145, 2.131,
+                                 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042]; // Two sided 95% t values for 1 to 30 degrees of freedom
+const ARRIVAL_STREAM_BASE : u64 = 16; // Each network station's arrivals get their own RNG stream, numbered from this one
+const SERVICE_START : f32 = 5.0 * 60.0; // Clock time (in minutes after midnight) the simulation's minute 0 stands for
+const TIMETABLE_RETRY : f32 = 0.5; // Minutes between checks for a train when a timetabled departure has none ready
+const LATE_DEPARTURE_TOLERANCE : f32 = 1.0; // Minutes after its scheduled time a departure still counts as on time
+const DEFAULT_STATION_IAT : f32 = 3.0; // Customer arrival rate for imported stations we have no ridership numbers for
+const EARTH_RADIUS : f32 = 6371.0; // km
+const SCHEMATIC_ORIGIN : (f32, f32) = (49.2827, -123.1207); // Latitude, longitude the first station of a line without real coordinates is put at
+const MAREY_PIXELS_PER_MINUTE : f32 = 6.0; // Horizontal scale of the time-distance diagram
+const MAREY_PIXELS_PER_KM : f32 = 20.0; // Vertical scale of the time-distance diagram
 const TRAIN_STOP_TIME : f32 = 0.05;
+const STATION_DWELL_TIME : f32 = 0.5; // Minutes a train stands at each station it stops at
 const FIRST_CUSTOMER_ARRIVALS_AT : f32 = 10.0;
 
-// USed to control what debug messages are printed during the simulation
-// NOTE: All of these slow down the simulation when toggled on and clutter the terminal
-const PRINT_TRAIN_INFO : bool = false;
-const PRINT_ARRIVAL_INFO : bool = false;
-const PRINT_CUSTOMER_INFO : bool = false;
-
-const PRINT_FULL_TIMES : bool = false; // Does not slow down sim considerably
-
+const HOURS_TRACKED : usize = 21; // Hour buckets used for per-hour stats (the last one only holds the very end of the day)
 const SIMULATION_LENGTH : f32 = 1200.0; // NOTE: PRODUCTION LENGTH = 20 HOURS = 1200 MINUTES
 
 // Used by trains for direction
 const EASTWARD : i8 = 1;
 const WESTWARD : i8 = -1;
 
+#[derive(Clone)]
 enum DispatchTypes {
     Constant(f32), // Release one train every lambda minutes
     TimeBased(f32), // Release trains based on the time graph, offset by lambda
     PopBased(f32), // Release trains based on the waiting population, scaled by lambda
     TransLink(), // Use translink's system
+    Timetable(), // Release trains at the exact times in each line's timetable
+}
+
+impl DispatchTypes {
+
+    fn from_name(name : &str, parameter : Option<f32>) -> Option<DispatchTypes> {
+        // Reads a dispatch method as given on the command line, methods left without a parameter get their default one
+        return match name.to_lowercase().as_str() {
+            "constant" => Some(DispatchTypes::Constant(parameter.unwrap_or(6.0))), // How many times a second (default is six seconds)
+            "timebased" => Some(DispatchTypes::TimeBased(parameter.unwrap_or(0.0))), // Offset added to time function (default is none)
+            "popbased" => Some(DispatchTypes::PopBased(parameter.unwrap_or(1.0))), // Multiplier to population function (default is none)
+            "translink" => Some(DispatchTypes::TransLink()),
+            "timetable" => Some(DispatchTypes::Timetable()),
+            _ => None,
+        };
+    }
+
+    fn parameter(&self) -> Option<f32> {
+        return match self {
+            DispatchTypes::Constant(parameter) | DispatchTypes::TimeBased(parameter) | DispatchTypes::PopBased(parameter) => Some(*parameter),
+            DispatchTypes::TransLink() | DispatchTypes::Timetable() => None,
+        };
+    }
+
+    fn with_parameter(&self, parameter : f32) -> Option<DispatchTypes> {
+        // The same kind of dispatch with a different parameter, None for dispatch types that don't have one
+        return match self {
+            DispatchTypes::Constant(_) => Some(DispatchTypes::Constant(parameter)),
+            DispatchTypes::TimeBased(_) => Some(DispatchTypes::TimeBased(parameter)),
+            DispatchTypes::PopBased(_) => Some(DispatchTypes::PopBased(parameter)),
+            DispatchTypes::TransLink() | DispatchTypes::Timetable() => None,
+        };
+    }
+
+    fn parameter_name(&self) -> &str {
+        return match self {
+            DispatchTypes::Constant(_) => "lambda",
+            DispatchTypes::TimeBased(_) => "offset",
+            DispatchTypes::PopBased(_) => "m",
+            DispatchTypes::TransLink() | DispatchTypes::Timetable() => "none",
+        };
+    }
+}
+
+#[derive(Clone)]
+enum ArrivalDraws { // How the time until a station's next customer is drawn
+    Ziggurat(), // rand_distr's exponential sampler (fastest, used unless antithetic runs are asked for)
+    Inverse(), // -ln(1 - U) / rate, the first run of an antithetic pair
+    Mirrored(), // -ln(U) / rate with the same U, the second run of an antithetic pair
+}
+
+#[derive(Clone)]
+enum SearchMethod {
+    Grid(usize), // Try this many evenly spaced parameters
+    Anneal(usize), // Simulated annealing for this many steps
+}
+
+#[derive(Clone)]
+enum Objective { // What the optimizer tries to make as small as possible
+    SocialCost(),
+    OperatingCost(),
+    PassengerCost(),
+    AverageWait(),
+    LostCustomers(),
+}
+
+impl Objective {
+
+    fn value(&self, bookkeeping : &Bookkeeper, costs : &CostModel) -> f32 {
+        return match self {
+            Objective::SocialCost() => bookkeeping.social_cost(costs),
+            Objective::OperatingCost() => bookkeeping.operating_cost(costs),
+            Objective::PassengerCost() => bookkeeping.passenger_cost(costs),
+            Objective::AverageWait() => bookkeeping.total_station_waiting_time / bookkeeping.total_customers_boarded,
+            Objective::LostCustomers() => bookkeeping.customers_balked + bookkeeping.customers_reneged + bookkeeping.customers_lost_to_closures,
+        };
+    }
+}
+
+#[derive(Clone)]
+enum CouplingRule {
+    Never(), // Trains keep the consist they were built with
+    Schedule(Vec<(f32, f32, u8)>), // FROM MINUTE, TO MINUTE, TARGET CARS (outside of these windows trains run as single units)
+    Demand(f32), // Target one car for every this many waiting customers
+}
+
+#[derive(Clone)]
+enum ConsistPreference {
+    FirstAvailable(), // Release whichever train has been waiting the longest
+    Largest(), // Release the highest capacity train that is waiting
+    Smallest(), // Release the lowest capacity train that is waiting
+    PeakLargest(), // Largest trains during peak hours, smallest trains otherwise
+}
+
+#[derive(Clone)]
+enum Disruption {
+    StationClosure(usize, usize, f32, f32), // LINE ID, STATION ID, FROM MINUTE, TO MINUTE
+    SingleTrack(usize, usize, usize, f32, f32), // LINE ID, FIRST STATION ID, LAST STATION ID, FROM MINUTE, TO MINUTE
 }
 
-struct Simulation { // Holds the Line and the list of trains on it
-    line : Line,
+#[derive(Clone)]
+enum StoppingPolicy {
+    AllStops(), // Every train stops everywhere
+    SkipStop(), // Trains alternate between A and B patterns, each skipping the other's minor stations
+    Express(u32), // Every n-th train only stops at major stations
+}
+
+#[derive(Clone)]
+struct StoppingPattern { // Which stations a train stops at
+    name: String,
+    stops: Vec<bool>,
+}
+
+#[derive(Clone)]
+struct ServicePattern { // A partial route run by some of the trains released during a window
+    line: usize,
+    first_station: usize,
+    last_station: usize, // One of the two ends has to be a terminal, the other needs a turnback track
+    from: f32,
+    to: f32,
+    every: u32, // Every n-th train released during the window runs this pattern
+    releases: u32, // Trains released from the pattern's terminal during the window so far
+}
+
+impl ServicePattern {
+
+    fn starts_from(&self, direction : i8, line_length : usize) -> bool {
+        // Is this pattern released from the terminal trains going the given direction leave from
+        if direction == EASTWARD {
+            return self.first_station == 0;
+        } else {
+            return self.last_station == line_length - 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SimOptions { // Everything needed to set up a simulation other than the seed
+    lines : Vec<Line>, // Every line in the network, as they are at the start of the day
+    route_choice : RouteChoice,
+    balk_queue_length : Option<usize>, // Customers arriving to a platform queue this long leave right away
+    mean_patience : Option<f32>, // Mean minutes customers wait before giving up, None if they wait forever
+    platform_capacity : Option<usize>, // Customers each platform can hold, None if there is no limit
+    meter_rate : usize,
+    arrival_draws : ArrivalDraws,
+    log_movements : bool, // Keep a record of every train's stops (for exporting the day's operations)
+    dispatch_type : DispatchTypes,
+    fleet : Fleet,
+    consist_preference : ConsistPreference,
+    coupling_rule : CouplingRule,
+    failures_enabled : bool,
+    disruptions : Vec<Disruption>,
+    turnbacks : Vec<(usize, usize)>, // LINE ID, STATION ID of stations with a turnback track (the terminals always have one)
+    service_patterns : Vec<ServicePattern>,
+    stopping_policy : StoppingPolicy,
+}
+
+struct Simulation { // Holds the Lines and the list of trains on them
+    lines : Vec<Line>,
+    network : Network,
+    fleet : Fleet,
     train_list : Vec<Train>,
+    consist_preference : ConsistPreference,
+    coupling_rule : CouplingRule,
+    stopping_policy : StoppingPolicy,
     time_elapsed : f32,
     future_event_list : BinaryHeap<DiscreteEvent>,
-    customer_iat : ChaCha8Rng,
+    events_added : u64, // Used to break ties between events at the same time in the order they were added
+    arrival_rngs : Vec<ChaCha8Rng>, // One per network station, decides when its customers arrive
+    destination_rng : ChaCha8Rng, // Decides where each customer is going
+    failure_rng : Option<ChaCha8Rng>, // Only exists when train failures are turned on
+    disruption_rng : ChaCha8Rng, // Decides what customers at closed stations do
+    route_rng : ChaCha8Rng, // Decides which route customers take when they pick at random
+    patience_rng : ChaCha8Rng, // Decides how long each customer is willing to wait
+    platform_rng : ChaCha8Rng, // Decides where along the platform customers wait
+    balk_queue_length : Option<usize>,
+    mean_patience : Option<f32>,
+    platform_capacity : Option<usize>,
+    meter_rate : usize, // Customers let through each station's faregates per minute while platforms are full
+    arrival_draws : ArrivalDraws,
+    log_movements : bool,
+    movements : Vec<Movement>, // Every station visit by every train, in the order they happened
+    trace : Option<std::io::BufWriter<std::fs::File>>, // Where every processed event is written, when tracing
+    log : Logger, // Debug messages, off unless turned on from the command line or TRANSIT_LOG
     bookkeeping : Bookkeeper,
     dispatch_type : DispatchTypes,
     translink_sampler : Vec<f32>,
@@ -76,7 +308,8 @@ impl Simulation {
 
     fn add_event(&mut self, event_type: EventTypes, time: f32) {
         // Add an event to the future event list
-        self.future_event_list.push(DiscreteEvent{event : event_type, time: time});
+        self.future_event_list.push(DiscreteEvent{event : event_type, time: time, sequence: self.events_added});
+        self.events_added += 1;
     }
 
     fn translink_time_sampler(&self) -> f32 {
@@ -103,23 +336,863 @@ impl Simulation {
         return self.timebased_sampler[bottom_hour];
     }
 
-    fn population_sampler(&self, multi : f32) -> f32 {
+    fn population_sampler(&self, line_id : usize, multi : f32) -> f32 {
         // A function that uses the amount of watinng customers to determine how soon to send out the next train
         // Min of 10.0, max of 2.5
-        // Fastest dispatch when 6 * TRAIN CAPACITY people are waiting
-        let trainfulls = self.bookkeeping.currently_waiting_customers / TRAIN_CAPACITY;
+        // Fastest dispatch when 6 * TRAIN CAPACITY people are waiting on the line
+        let trainfulls = self.lines[line_id].waiting_customers() / self.fleet.average_capacity();
         let trainfuls_normalized = clamp(trainfulls * multi / 6.0, 0.0, 1.0);
 
         return ((1.0 - trainfuls_normalized) * 10.0 ) + (trainfuls_normalized * 2.5);
     }
+
+    fn expected_customers(&self) -> f32 {
+        // How many customers a day should bring on average, every station gets its first customer right away then
+        // arrives at its hourly rate until the end of the day
+        // NOTE: Approximate, the next arrival is drawn at the rate in effect when the last customer arrived
+        let step = 0.1;
+        let mut expected = 0.0;
+        for network_station in 0..self.network.length() {
+            let (line_id, station_id) = self.network.home_stations[network_station];
+            expected += 1.0;
+            let mut time = FIRST_CUSTOMER_ARRIVALS_AT + (step / 2.0);
+            while time < SIMULATION_LENGTH {
+                expected += self.lines[line_id].stations[station_id].get_true_iat(time) * step;
+                time += step;
+            }
+        }
+        return expected;
+    }
+
+    fn is_peak(&self) -> bool {
+        // Peak hours are the hours where TransLink runs their most frequent service
+        return self.translink_time_sampler() <= PEAK_HEADWAY;
+    }
+
+    fn launch_train(&mut self, line_id : usize, direction : i8) -> Option<f32> {
+        // Sends the next train waiting at the terminal out in the given direction
+        // Returns how long it is held up by coupling work, or None if there was no train to send
+        self.recombine_consists(line_id, direction);
+        let position = self.pick_train(line_id, direction);
+        let released = if direction == EASTWARD {self.lines[line_id].release_eastward(position)} else {self.lines[line_id].release_westward(position)};
+        let train_id = match released {
+            Some(train_id) => train_id,
+            None => {
+                self.log.write(LogCategory::Train(), LogLevel::Warn(), self.time_elapsed, None, None,
+                    || format!("UNABLE TO RELEASE TRAIN {}!", if direction == EASTWARD {"EASTWARD"} else {"WESTWARD"}));
+                return None;
+            }
+        };
+
+        // Put train on the first station it comes to (station 0 going east, the last station going west)
+        let first_station = if direction == EASTWARD {0} else {self.lines[line_id].length() - 1};
+        self.train_list[train_id].active = true;
+        let cars = self.train_list[train_id].cars as usize;
+        self.train_list[train_id].car_riders.resize(cars, 0.0); // Consist may have been coupled or uncoupled
+        self.train_list[train_id].direction = direction;
+        self.train_list[train_id].at_station = first_station;
+        self.train_list[train_id].trip += 1;
+        (self.train_list[train_id].route_first, self.train_list[train_id].route_last) = self.pick_route(line_id, direction);
+        self.train_list[train_id].pattern = self.pick_stopping_pattern(line_id, direction);
+        self.schedule_failure(train_id);
+        let coupling_delay = (self.train_list[train_id].ready_at - self.time_elapsed).max(0.0);
+        let platform_time = if direction == EASTWARD {0.5} else {TRAIN_STOP_TIME};
+        self.add_event(EventTypes::TrainArrival(train_id, first_station), self.time_elapsed + coupling_delay + platform_time);
+        self.log.write(LogCategory::Train(), LogLevel::Info(), self.time_elapsed, Some(train_id), Some(self.lines[line_id].id_to_name(first_station)),
+            || format!("Train {} RELEASED going {}", train_id, if direction == EASTWARD {"EAST"} else {"WEST"}));
+        return Some(coupling_delay);
+    }
+
+    fn log_arrival(&mut self, train_id : usize, station_id : usize, stopped : bool) {
+        // Starts the record of a train's visit to a station, the departure time is filled in when it leaves
+        if !self.log_movements { return; }
+        let train = &self.train_list[train_id];
+        self.movements.push(Movement {train: train_id, line: train.line, trip: train.trip, direction: train.direction, station: station_id,
+            arrival: self.time_elapsed, departure: self.time_elapsed, stopped: stopped, riders: train.riding_customers, capacity: train.capacity});
+        self.train_list[train_id].movement = Some(self.movements.len() - 1);
+    }
+
+    fn log_departure(&mut self, train_id : usize) {
+        if !self.log_movements { return; }
+        if let Some(index) = self.train_list[train_id].movement.take() {
+            self.movements[index].departure = self.time_elapsed;
+            self.movements[index].riders = self.train_list[train_id].riding_customers;
+            self.movements[index].capacity = self.train_list[train_id].capacity;
+        }
+    }
+
+    fn movement_trips(&self) -> Vec<Vec<usize>> {
+        // Groups the logged station visits into trips, a trip ends at a terminal or where a train turns back
+        let mut open_trip: HashMap<usize, usize> = HashMap::new(); // TRAIN ID -> index of its trip in the list
+        let mut trips: Vec<Vec<usize>> = Vec::new();
+        for index in 0..self.movements.len() {
+            let movement = &self.movements[index];
+            let continues = open_trip.get(&movement.train).is_some_and(|&trip| {
+                let last = &self.movements[*trips[trip].last().unwrap()];
+                last.trip == movement.trip && last.direction == movement.direction
+            });
+            if continues {
+                trips[open_trip[&movement.train]].push(index);
+            } else {
+                open_trip.insert(movement.train, trips.len());
+                trips.push(vec![index]);
+            }
+        }
+        trips.retain(|trip| trip.len() >= 2);
+        return trips;
+    }
+
+    fn export_gtfs(&self, directory : &str) -> Result<(), String> {
+        // Writes the day's train movements as a GTFS feed (agency.txt, stops.txt, routes.txt, trips.txt, stop_times.txt and calendar.txt)
+        // Stop IDs are the station names and block IDs are the train IDs, every trip runs on service SIM, which runs every day
+        // Stations a train passed through without stopping have no pick up or drop off
+        std::fs::create_dir_all(directory).map_err(|e| format!("Could not create {}: {}", directory, e))?;
+        let write = |file : &str, contents : String| -> Result<(), String> {
+            let path = std::path::Path::new(directory).join(file);
+            return std::fs::write(&path, contents).map_err(|e| format!("Could not write {}: {}", path.display(), e));
+        };
+
+        let agency = String::from("agency_name,agency_url,agency_timezone\nTransLink,https://www.translink.ca,America/Vancouver\n");
+        let calendar = String::from("service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\nSIM,1,1,1,1,1,1,1,20000101,20991231\n");
+
+        let mut stops = String::from("stop_id,stop_name,stop_lat,stop_lon\n");
+        for network_station in 0..self.network.length() {
+            let (line_id, station_id) = self.network.home_stations[network_station];
+            let name = csv_field(self.network.id_to_name(network_station));
+            let (lat, lon) = self.lines[line_id].positions[station_id];
+            stops += &format!("{},{},{:.6},{:.6}\n", name, name, lat, lon);
+        }
+
+        let mut routes = String::from("route_id,route_short_name,route_long_name,route_type\n");
+        for line in self.lines.iter() {
+            let name = csv_field(&line.name);
+            routes += &format!("{},{},{},1\n", name, name, name);
+        }
+
+        let mut trips = String::from("route_id,service_id,trip_id,direction_id,block_id\n");
+        let mut stop_times = String::from("trip_id,arrival_time,departure_time,stop_id,stop_sequence,pickup_type,drop_off_type\n");
+        let trip_list = self.movement_trips();
+        for trip_index in 0..trip_list.len() {
+            let first = &self.movements[trip_list[trip_index][0]];
+            let trip_id = format!("SIM-{}", trip_index + 1);
+            let direction_id = if first.direction == EASTWARD {0} else {1};
+            trips += &format!("{},SIM,{},{},{}\n", csv_field(&self.lines[first.line].name), trip_id, direction_id, first.train);
+            for sequence in 0..trip_list[trip_index].len() {
+                let movement = &self.movements[trip_list[trip_index][sequence]];
+                let service = if movement.stopped {0} else {1};
+                stop_times += &format!("{},{},{},{},{},{},{}\n", trip_id, format_clock_time(movement.arrival), format_clock_time(movement.departure),
+                    csv_field(self.lines[movement.line].id_to_name(movement.station)), sequence + 1, service, service);
+            }
+        }
+
+        write("agency.txt", agency)?;
+        write("stops.txt", stops)?;
+        write("routes.txt", routes)?;
+        write("trips.txt", trips)?;
+        write("stop_times.txt", stop_times)?;
+        write("calendar.txt", calendar)?;
+        return Ok(());
+    }
+
+    fn write_marey_diagram(&self, path : &str, window : (f32, f32)) -> Result<(), String> {
+        // Draws every trip as a time-distance string line in an SVG, one panel per line with its stations down the side
+        // Each stretch is coloured by how full the train was when it left the station before it, from green (empty) to red (full)
+        // Trains bunching up show as lines running close together, gaps as wide spaces between them
+        let (start, end) = window;
+        let (left, top, panel_gap) = (220.0, 40.0, 60.0);
+        let x = |minutes : f32| left + (minutes - start) * MAREY_PIXELS_PER_MINUTE;
+        let width = x(end) + 40.0;
+
+        let mut panel_tops = Vec::new();
+        let mut positions: Vec<Vec<f32>> = Vec::new(); // Per line, how far down the panel each station is
+        let mut height = top;
+        for line in self.lines.iter() {
+            let mut position = vec![0.0];
+            for distance in line.inter_station_distances.iter() {
+                position.push(position[position.len() - 1] + distance * MAREY_PIXELS_PER_KM);
+            }
+            panel_tops.push(height + 20.0);
+            height += 20.0 + position[position.len() - 1] + panel_gap;
+            positions.push(position);
+        }
+        height += 40.0;
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" font-family=\"sans-serif\" font-size=\"11\">\n", width, height);
+        svg += &format!("<rect width=\"{:.0}\" height=\"{:.0}\" fill=\"white\"/>\n", width, height);
+
+        for line_id in 0..self.lines.len() {
+            let (panel_top, bottom) = (panel_tops[line_id], panel_tops[line_id] + positions[line_id][positions[line_id].len() - 1]);
+            svg += &format!("<text x=\"{}\" y=\"{:.1}\" font-size=\"14\" font-weight=\"bold\">{}</text>\n", left, panel_top - 12.0, xml_escape(&self.lines[line_id].name));
+            svg += &format!("<clipPath id=\"panel{}\"><rect x=\"{}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\"/></clipPath>\n",
+                line_id, left, panel_top - 2.0, x(end) - left, bottom - panel_top + 4.0);
+
+            // Stations across, hours down
+            for station in 0..self.lines[line_id].length() {
+                let y = panel_top + positions[line_id][station];
+                svg += &format!("<line x1=\"{}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#ddd\"/>\n", left, y, x(end), y);
+                svg += &format!("<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\" dominant-baseline=\"middle\">{}</text>\n",
+                    left - 6.0, y, xml_escape(self.lines[line_id].id_to_name(station)));
+            }
+            let mut hour = (start / 60.0).ceil() * 60.0;
+            while hour <= end {
+                svg += &format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#bbb\"/>\n", x(hour), panel_top, x(hour), bottom);
+                svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n", x(hour), bottom + 14.0, &format_clock_time(hour)[..5]);
+                hour += 60.0;
+            }
+
+            svg += &format!("<g clip-path=\"url(#panel{})\" stroke-width=\"1.5\" fill=\"none\">\n", line_id);
+            for trip in self.movement_trips() {
+                if self.movements[trip[0]].line != line_id { continue; }
+                for leg in 0..trip.len() {
+                    let from = &self.movements[trip[leg]];
+                    let colour = load_colour(from.riders / from.capacity);
+                    let y = panel_top + positions[line_id][from.station];
+                    if from.departure > from.arrival && from.departure >= start && from.arrival <= end {
+                        svg += &format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{}\"/>\n", x(from.arrival), y, x(from.departure), y, colour);
+                    }
+                    if leg + 1 == trip.len() { continue; }
+                    let to = &self.movements[trip[leg + 1]];
+                    if to.arrival < start || from.departure > end { continue; }
+                    svg += &format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{}\"/>\n",
+                        x(from.departure), y, x(to.arrival), panel_top + positions[line_id][to.station], colour);
+                }
+            }
+            svg += "</g>\n";
+        }
+
+        // Load factor legend
+        let legend_y = height - 24.0;
+        svg += &format!("<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\" dominant-baseline=\"middle\">LOAD FACTOR</text>\n", left - 6.0, legend_y);
+        for step in 0..5 {
+            let load = step as f32 * 0.25;
+            let legend_x = left + step as f32 * 70.0;
+            svg += &format!("<line x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\" stroke=\"{}\" stroke-width=\"4\"/>\n", legend_x, legend_y, legend_x + 24.0, legend_y, load_colour(load));
+            svg += &format!("<text x=\"{}\" y=\"{:.1}\" dominant-baseline=\"middle\">{:.0}%</text>\n", legend_x + 28.0, legend_y, load * 100.0);
+        }
+        svg += "</svg>\n";
+
+        return std::fs::write(path, svg).map_err(|e| format!("Could not write {}: {}", path, e));
+    }
+
+    fn station_waiting(&self, network_station : usize) -> usize {
+        // Customers waiting at a network station: on the platforms of every line through it and held at its faregates
+        // The same count as currently_waiting_customers, just for one station
+        let mut waiting = 0;
+        for line in self.lines.iter() {
+            if let Some(station_id) = line.network_ids.iter().position(|id| *id == network_station) {
+                let station = &line.stations[station_id];
+                waiting += station.east_customers.len() + station.west_customers.len() + station.concourse.len();
+            }
+        }
+        return waiting;
+    }
+
+    fn trace_event(&mut self, event : &DiscreteEvent) {
+        // Writes one processed event to the trace as a line of JSON, with the state it left behind
+        // Train events give where the train is and what it carries, station events how many customers are waiting there
+        // A moving train's station is the one it last left (next_station is where it is headed), so a departure gives the waiting left after boarding
+        // PollStats lists every station's waiting once a minute, which also catches customers giving up or being sent away
+        let mut record = format!("{{\"seq\":{},\"time\":{:.3}", event.sequence, event.time);
+        let train_state = |sim : &Simulation, train_id : usize| -> String {
+            let train = &sim.train_list[train_id];
+            let line = &sim.lines[train.line];
+            let station_id = if train.in_motion {(train.at_station as i64 - train.direction as i64).clamp(0, line.length() as i64 - 1) as usize} else {train.at_station};
+            let mut state = format!(",\"train\":{},\"line\":\"{}\",\"station\":\"{}\",\"direction\":{},\"moving\":{},\"active\":{},\"out_of_service\":{},\"riders\":{}",
+                train_id, json_escape(&line.name), json_escape(line.id_to_name(station_id)), train.direction,
+                train.in_motion, train.active, train.out_of_service, train.riding_customers);
+            if train.in_motion {
+                state += &format!(",\"next_station\":\"{}\"", json_escape(line.id_to_name(train.at_station)));
+            }
+            state += &format!(",\"waiting\":{}", sim.station_waiting(line.network_ids[station_id]));
+            return state;
+        };
+        match event.event {
+            EventTypes::TrainArrival(train_id, _) => record += &format!(",\"event\":\"TrainArrival\"{}", train_state(self, train_id)),
+            EventTypes::TrainDeparture(train_id, _) => record += &format!(",\"event\":\"TrainDeparture\"{}", train_state(self, train_id)),
+            EventTypes::TrainFailure(train_id, trip) => record += &format!(",\"event\":\"TrainFailure\",\"trip\":{}{}", trip, train_state(self, train_id)),
+            EventTypes::TrainRescue(train_id) => record += &format!(",\"event\":\"TrainRescue\"{}", train_state(self, train_id)),
+            EventTypes::TrainRelease(line_id, direction) => record += &format!(",\"event\":\"TrainRelease\",\"line\":\"{}\",\"direction\":{},\"trains_deployed\":{}",
+                json_escape(&self.lines[line_id].name), direction, self.lines[line_id].trains_deployed),
+            EventTypes::ScheduledRelease(line_id, direction, index) => record += &format!(",\"event\":\"ScheduledRelease\",\"line\":\"{}\",\"direction\":{},\"departure\":{},\"trains_deployed\":{}",
+                json_escape(&self.lines[line_id].name), direction, index, self.lines[line_id].trains_deployed),
+            EventTypes::StationClosure(line_id, station_id) => record += &format!(",\"event\":\"StationClosure\",\"line\":\"{}\",\"station\":\"{}\",\"waiting\":{}",
+                json_escape(&self.lines[line_id].name), json_escape(self.lines[line_id].id_to_name(station_id)), self.station_waiting(self.lines[line_id].network_ids[station_id])),
+            EventTypes::CustomerArrival(network_station) => record += &format!(",\"event\":\"CustomerArrival\",\"station\":\"{}\",\"waiting\":{}",
+                json_escape(self.network.id_to_name(network_station)), self.station_waiting(network_station)),
+            EventTypes::PollStats() => {
+                record += &format!(",\"event\":\"PollStats\",\"total_waiting\":{}", self.bookkeeping.currently_waiting_customers);
+                for network_station in 0..self.network.length() {
+                    record += &format!(",\"waiting@{}\":{}", json_escape(self.network.id_to_name(network_station)), self.station_waiting(network_station));
+                }
+            },
+            EventTypes::Dummy() => record += ",\"event\":\"Dummy\"",
+        }
+        record += "}";
+
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(e) = writeln!(trace, "{}", record) {
+                println!("ERROR: Could not write the event trace, it stops here: {}", e);
+                self.trace = None;
+            }
+        }
+    }
+
+    fn export_timetable(&self, path : &str) -> Result<(), String> {
+        // Writes the day's train movements as a timetable, one row per trip and one column per station
+        // Each cell is the time the train left that station, | where it passed through and blank where it never went
+        let stations = self.network.station_names.len();
+        let mut csv = String::from("line,train,direction");
+        for station in 0..stations {
+            csv += &format!(",{}", csv_field(self.network.id_to_name(station)));
+        }
+        csv += "\n";
+
+        for trip in self.movement_trips() {
+            let first = &self.movements[trip[0]];
+            let mut cells = vec![String::new(); stations];
+            for index in trip.iter() {
+                let movement = &self.movements[*index];
+                let network_station = self.lines[movement.line].network_ids[movement.station];
+                cells[network_station] = if movement.stopped {format_clock_time(movement.departure)} else {String::from("|")};
+            }
+            let direction = if first.direction == EASTWARD {"east"} else {"west"};
+            csv += &format!("{},{},{},{}\n", csv_field(&self.lines[first.line].name), first.train, direction, cells.join(","));
+        }
+
+        return std::fs::write(path, csv).map_err(|e| format!("Could not write {}: {}", path, e));
+    }
+
+    fn pick_train(&self, line_id : usize, direction : i8) -> usize {
+        // Returns the position in the direction's ready queue of the train we should release next
+        let queue = if direction == EASTWARD {&self.lines[line_id].east_trains} else {&self.lines[line_id].west_trains};
+
+        let prefer_largest = match self.consist_preference {
+            ConsistPreference::FirstAvailable() => return 0,
+            ConsistPreference::Largest() => true,
+            ConsistPreference::Smallest() => false,
+            ConsistPreference::PeakLargest() => self.is_peak(),
+        };
+
+        // Ties go to the train that has been waiting the longest
+        let mut best_position = 0;
+        for position in 1..queue.len() {
+            let capacity = self.train_list[queue[position]].capacity;
+            let best_capacity = self.train_list[queue[best_position]].capacity;
+            if (prefer_largest && capacity > best_capacity) || (!prefer_largest && capacity < best_capacity) {
+                best_position = position;
+            }
+        }
+        return best_position;
+    }
+
+    fn schedule_failure(&mut self, train_id : usize) {
+        // Draws when the given train will fail during the trip it is starting, if the trip ends first nothing happens
+        let train_type = &self.fleet.types[self.train_list[train_id].type_id];
+        let ttf = Exp::new(1.0 / train_type.mtbf).unwrap();
+        let failure_rng = match &mut self.failure_rng {
+            Some(rng) => rng,
+            None => return,
+        };
+        let time_to_failure = ttf.sample(failure_rng);
+        let trip = self.train_list[train_id].trip;
+        self.add_event(EventTypes::TrainFailure(train_id, trip), self.time_elapsed + time_to_failure);
+    }
+
+    fn block_delay(&self, line_id : usize, direction : i8, station_id : usize) -> f32 {
+        // How long a train has to hold before it can head towards the given station
+        let line = &self.lines[line_id];
+        let blocked_until = if direction == EASTWARD {line.east_blocked_until[station_id]} else {line.west_blocked_until[station_id]};
+        return (blocked_until - self.time_elapsed).max(0.0);
+    }
+
+    fn leave_single_track(&mut self, train_id : usize, station_id : usize) {
+        // Takes the train out of any single tracked section it just left, once a section is clear the trains waiting for it get to go
+        let direction = self.train_list[train_id].direction;
+        let line_id = self.train_list[train_id].line;
+        let mut released = Vec::new();
+        for section in self.lines[line_id].single_track.iter_mut() {
+            if !section.exited_by(direction, station_id) || !section.trains_inside.contains(&train_id) { continue; }
+            section.trains_inside.retain(|&id| id != train_id);
+
+            if section.trains_inside.is_empty() {
+                // Trains going the other way get first go
+                let waiting: Vec<(usize, usize)> = section.waiting.drain(..).collect();
+                for other_way in [true, false] {
+                    for &(waiting_id, next_station) in &waiting {
+                        if (self.train_list[waiting_id].direction != section.direction) == other_way {
+                            released.push((waiting_id, next_station));
+                        }
+                    }
+                }
+            }
+        }
+        for (waiting_id, next_station) in released {
+            self.add_event(EventTypes::TrainDeparture(waiting_id, next_station), self.time_elapsed);
+        }
+    }
+
+    fn pick_route(&mut self, line_id : usize, direction : i8) -> (usize, usize) {
+        // Decides which stations a train being released in the given direction will serve
+        let line_length = self.lines[line_id].length();
+        for pattern in self.lines[line_id].service_patterns.iter_mut() {
+            if !pattern.starts_from(direction, line_length) || self.time_elapsed < pattern.from || self.time_elapsed >= pattern.to {
+                continue;
+            }
+            pattern.releases += 1;
+            if pattern.releases % pattern.every == 0 {
+                return (pattern.first_station, pattern.last_station);
+            }
+        }
+        return (0, line_length - 1);
+    }
+
+    fn pick_stopping_pattern(&mut self, line_id : usize, direction : i8) -> usize {
+        // Decides which stopping pattern a train being released in the given direction will run
+        let direction_index = if direction == EASTWARD {0} else {1};
+        let releases = self.lines[line_id].stopping_releases[direction_index];
+        self.lines[line_id].stopping_releases[direction_index] += 1;
+
+        match self.stopping_policy {
+            StoppingPolicy::AllStops() => return 0,
+            StoppingPolicy::SkipStop() => return (releases % 2) as usize, // A, B, A, B...
+            StoppingPolicy::Express(every) => return if releases % every == every - 1 {1} else {0},
+        }
+    }
+
+    fn train_serves(&self, train_id : usize, station_id : usize) -> bool {
+        // Returns true if the train stops at the given station on its current trip
+        let train = &self.train_list[train_id];
+        return train.serves(station_id) && self.lines[train.line].stopping_patterns[train.pattern].stops[station_id];
+    }
+
+    fn customer_alights(&mut self, mut customer : Customer, station_id : usize) {
+        // Takes a customer off a train, they either start the next leg of their trip here or are done with it
+        customer.tet = self.time_elapsed;
+        customer.rode += customer.tet - customer.tbt;
+
+        if customer.end_at == station_id && !customer.itinerary.is_empty() {
+            // Transfer, changing lines means walking to the other line's platform first
+            let leg = customer.itinerary.pop_front().expect("ERR: NO NEXT LEG");
+            let changes_line = leg.line != customer.line;
+            let walk_time = if changes_line {TRANSFER_WALK_TIME} else {0.0};
+            customer.line = leg.line;
+            customer.start_at = leg.from;
+            customer.end_at = leg.to;
+            customer.sat = self.time_elapsed + walk_time;
+            customer.transferring = changes_line;
+            self.bookkeeping.transfers += 1.0;
+            self.bookkeeping.total_customers_boarded -= 1.0; // They will be counted again when they re-board
+            self.bookkeeping.currently_waiting_customers += 1.0;
+            self.lines[leg.line].stations[leg.from].add_customer(customer);
+        } else if customer.end_at != station_id && !customer.itinerary.is_empty() {
+            // Got off a stop early because the station they were changing trains at is closed, their trip isn't over
+            self.reroute_customer(customer, station_id);
+        } else {
+            self.bookkeeping.total_customers_departed += 1.0;
+            self.bookkeeping.record_trip(&customer);
+        }
+    }
+
+    fn reroute_customer(&mut self, mut customer : Customer, station_id : usize) {
+        // Finds another route from where they got off that doesn't change trains at a closed station
+        // Without one they can't finish the trip and are lost to the closure
+        let now = self.time_elapsed;
+        let here = self.lines[customer.line].network_ids[station_id];
+        if here == customer.destination {
+            self.bookkeeping.total_customers_departed += 1.0;
+            self.bookkeeping.record_trip(&customer);
+            return;
+        }
+        let mut legs: VecDeque<Leg> = match self.network.route_avoiding(&self.lines, here, customer.destination, now) {
+            Some(route) => route.legs.into_iter().collect(),
+            None => {
+                self.bookkeeping.customers_lost_to_closures += 1.0;
+                return;
+            }
+        };
+        let first = legs.pop_front().expect("ERR: ROUTE WITHOUT LEGS");
+        let changes_line = first.line != customer.line;
+        customer.line = first.line;
+        customer.start_at = first.from;
+        customer.end_at = first.to;
+        customer.itinerary = legs;
+        customer.sat = now + if changes_line {TRANSFER_WALK_TIME} else {0.0};
+        customer.transferring = changes_line;
+        self.bookkeeping.customers_rerouted += 1.0;
+        self.bookkeeping.total_customers_boarded -= 1.0; // They will be counted again when they re-board
+        self.bookkeeping.currently_waiting_customers += 1.0;
+        self.lines[first.line].stations[first.from].add_customer(customer);
+    }
+
+    fn pick_platform_position(&mut self, line_id : usize, station_id : usize) -> f32 {
+        // Most customers wait close to the entrance they came in by, the rest spread out along the platform
+        let entrances = &self.lines[line_id].stations[station_id].entrances;
+        if rand::Rng::gen::<f32>(&mut self.platform_rng) >= PLATFORM_BUNCHING || entrances.is_empty() {
+            return rand::Rng::gen::<f32>(&mut self.platform_rng);
+        }
+        let entrance = entrances[rand::Rng::gen_range(&mut self.platform_rng, 0..entrances.len())];
+        let spread = Normal::new(entrance, ENTRANCE_SPREAD).unwrap();
+        return clamp(spread.sample(&mut self.platform_rng), 0.0, 0.999);
+    }
+
+    fn leave_behind(&mut self, train_id : usize, station_id : usize) -> usize {
+        // Marks everyone on the train's platform who would have boarded it if it wasn't full, returns how many there were
+        let line_id = self.train_list[train_id].line;
+        let direction = self.train_list[train_id].direction;
+        let queue = if direction == EASTWARD {&self.lines[line_id].stations[station_id].east_customers} else {&self.lines[line_id].stations[station_id].west_customers};
+        let mut passed_up = Vec::new();
+        for position in 0..queue.len() {
+            if self.train_serves(train_id, queue[position].end_at) && queue[position].sat <= self.time_elapsed {
+                passed_up.push(position);
+            }
+        }
+
+        let queue = if direction == EASTWARD {&mut self.lines[line_id].stations[station_id].east_customers} else {&mut self.lines[line_id].stations[station_id].west_customers};
+        for position in &passed_up {
+            queue[*position].times_left_behind += 1;
+        }
+        let network_station = self.lines[line_id].network_ids[station_id];
+        let hour = ((self.time_elapsed / 60.0).floor() as usize).min(HOURS_TRACKED - 1);
+        self.bookkeeping.left_behind[network_station * HOURS_TRACKED + hour] += passed_up.len() as f32;
+        return passed_up.len();
+    }
+
+    fn record_energy(&mut self, train_id : usize, segment : usize, minutes : f32, from_stop : bool, to_stop : bool) {
+        // Adds the energy a train draws running over one segment of its line
+        // A train starting from a stop gets up to line speed, and only gets braking energy back if it stops at the next station
+        let train = &self.train_list[train_id];
+        let line = &self.lines[train.line];
+        let mass = (train.empty_mass * 1000.0) + (train.riding_customers * PASSENGER_MASS); // kg
+        let distance = line.inter_station_distances[segment] * 1000.0; // m
+        let grade = line.inter_station_gradients[segment] * train.direction as f32 / 100.0;
+
+        let kinetic = 0.5 * mass * LINE_SPEED * LINE_SPEED;
+        let accelerating = if from_stop {kinetic} else {0.0};
+        let braking = if to_stop {kinetic} else {0.0};
+        let resistance = (mass * GRAVITY * ROLLING_RESISTANCE * distance) + (mass * GRAVITY * grade * distance); // Downhill runs can have energy to spare
+        let traction = (accelerating + resistance.max(0.0)) / MOTOR_EFFICIENCY;
+        let recovered = REGEN_RECOVERY * (braking + (-resistance).max(0.0));
+        let kwh = ((traction - recovered) / 3600000.0) + (AUXILIARY_POWER * train.cars as f32 * minutes / 60.0);
+
+        // Coupled units share the consist's energy by how many cars they bring
+        let lead_share = self.train_list[train_id].cars as f32;
+        for unit_index in 0..self.train_list[train_id].coupled_units.len() {
+            let unit = self.train_list[train_id].coupled_units[unit_index];
+            let unit_cars = self.train_list[unit].cars as f32;
+            self.train_list[unit].energy_used += kwh * unit_cars / lead_share;
+        }
+        let unit_cars : f32 = self.train_list[train_id].coupled_units.iter().map(|&unit| self.train_list[unit].cars as f32).sum();
+        self.train_list[train_id].energy_used += kwh * (lead_share - unit_cars) / lead_share;
+
+        let line = &self.lines[self.train_list[train_id].line];
+        let (a, b) = (line.network_ids[segment], line.network_ids[segment + 1]);
+        let hour = ((self.time_elapsed / 60.0).floor() as usize).min(HOURS_TRACKED - 1);
+        self.bookkeeping.energy_kwh += kwh;
+        self.bookkeeping.hourly_energy[hour] += kwh;
+        self.bookkeeping.segment_energy[a.min(b) * self.bookkeeping.od_stations + a.max(b)] += kwh;
+        self.bookkeeping.passenger_km += self.train_list[train_id].riding_customers * line.inter_station_distances[segment];
+    }
+
+    fn meter_platforms(&mut self, line_id : usize, station_id : usize) {
+        // Lets up to the metering rate of held customers through the faregates, as long as their platform has room
+        // Also keeps track of how crowded the platforms and concourse are, called once a minute
+        let now = self.time_elapsed;
+        let network_station = self.lines[line_id].network_ids[station_id];
+        let station = &mut self.lines[line_id].stations[station_id];
+        let mut admitted = 0;
+        let mut position = 0;
+        while position < station.concourse.len() && admitted < self.meter_rate {
+            if station.queue_length(station.concourse[position].get_direction()) >= station.platform_capacity {
+                position += 1; // Their platform is still full
+                continue;
+            }
+            let customer = station.concourse.remove(position).expect("ERR: EMPTY CONCOURSE");
+            self.bookkeeping.gate_hold_time += now - customer.sat;
+            station.add_customer(customer);
+            admitted += 1;
+        }
+
+        let concourse_length = station.concourse.len() as f32;
+        self.bookkeeping.concourse_length_total[network_station] += concourse_length;
+        self.bookkeeping.concourse_length_max[network_station] = self.bookkeeping.concourse_length_max[network_station].max(concourse_length);
+        for direction in [EASTWARD, WESTWARD] {
+            let on_platform = station.queue_length(direction);
+            if on_platform >= station.platform_capacity { self.bookkeeping.platform_full_minutes[network_station] += 1.0; }
+            if on_platform > station.platform_capacity { self.bookkeeping.platform_over_minutes[network_station] += 1.0; }
+        }
+    }
+
+    fn renege_customers(&mut self, line_id : usize, station_id : usize) {
+        // Customers who have run out of patience leave the platform, they are lost ridership
+        let now = self.time_elapsed;
+        let mut reneged = 0.0;
+        let mut waited = 0.0;
+        let mut gives_up = |customer : &Customer| {
+            if customer.sat + customer.patience > now { return true; }
+            reneged += 1.0;
+            waited += now - customer.sat;
+            return false;
+        };
+        let station = &mut self.lines[line_id].stations[station_id];
+        station.east_customers.retain(&mut gives_up);
+        station.west_customers.retain(&mut gives_up);
+        station.concourse.retain(&mut gives_up);
+
+        self.bookkeeping.customers_reneged += reneged;
+        self.bookkeeping.reneged_waiting_time += waited;
+        self.bookkeeping.currently_waiting_customers -= reneged;
+    }
+
+    fn detrain_to_platform(&mut self, train_id : usize, station_id : usize) {
+        // Lets everyone off the train at the given station, anyone who isn't there yet has to wait for another train
+        let line_id = self.train_list[train_id].line;
+        while let Some(mut customer) = self.train_list[train_id].customer_list.pop() {
+            if customer.end_at == station_id {
+                self.customer_alights(customer, station_id);
+                continue;
+            }
+            customer.rode += self.time_elapsed - customer.tbt;
+            customer.sat = self.time_elapsed;
+            customer.platform_position = (customer.car as f32 + 0.5) / self.train_list[train_id].cars as f32; // Gets off in front of their car
+            customer.start_at = station_id;
+            self.bookkeeping.total_customers_boarded -= 1.0; // They will be counted again when they re-board
+            self.bookkeeping.currently_waiting_customers += 1.0;
+            self.lines[line_id].stations[station_id].add_customer(customer);
+        }
+        self.train_list[train_id].riding_customers = 0.0;
+        self.train_list[train_id].car_riders.fill(0.0);
+    }
+
+    fn divert_customer(&mut self, mut customer : Customer, already_waiting : bool) {
+        // Sends a customer away from a closed station, either to the next station towards their destination or off the system
+        let neighbour = if customer.end_at > customer.start_at {customer.start_at + 1} else {customer.start_at - 1};
+        let diverts = rand::Rng::gen::<f32>(&mut self.disruption_rng) < DIVERT_PROBABILITY;
+
+        let line_id = customer.line;
+        if diverts && neighbour != customer.end_at && !self.lines[line_id].is_closed(neighbour, self.time_elapsed) {
+            customer.start_at = neighbour;
+            if self.lines[line_id].stations[neighbour].enter_through_gates(customer) {
+                self.bookkeeping.customers_held_at_gates += 1.0;
+            }
+            self.bookkeeping.customers_diverted += 1.0;
+            if !already_waiting {self.bookkeeping.currently_waiting_customers += 1.0;}
+        } else {
+            self.bookkeeping.customers_lost_to_closures += 1.0;
+            if already_waiting {self.bookkeeping.currently_waiting_customers -= 1.0;}
+        }
+    }
+
+    fn target_cars(&self) -> Option<u8> {
+        // How long trains leaving a terminal should be right now, None if consists should be left alone
+        match &self.coupling_rule {
+            CouplingRule::Never() => return None,
+            CouplingRule::Schedule(windows) => {
+                for (from, to, cars) in windows {
+                    if self.time_elapsed >= *from && self.time_elapsed < *to {
+                        return Some(*cars);
+                    }
+                }
+                return Some(0); // Run single units
+            },
+            CouplingRule::Demand(customers_per_car) => {
+                let cars = (self.bookkeeping.currently_waiting_customers / customers_per_car).ceil();
+                return Some(clamp(cars, 0.0, MAX_CONSIST_CARS as f32) as u8);
+            },
+        }
+    }
+
+    fn recombine_consists(&mut self, line_id : usize, direction : i8) {
+        // Couples or uncouples the trains resting in a terminal's queue so they get as close to the target length as possible
+        // NOTE: Only trains of the same type can be coupled together
+        let target = match self.target_cars() {
+            Some(cars) => cars,
+            None => return,
+        };
+        let line = &mut self.lines[line_id];
+        let mut queue = if direction == EASTWARD {std::mem::take(&mut line.east_trains)} else {std::mem::take(&mut line.west_trains)};
+
+        // Uncouple anything that is too long, the freed units wait at the back of the queue
+        for position in 0..queue.len() {
+            let lead = queue[position];
+            while self.train_list[lead].cars > target && !self.train_list[lead].coupled_units.is_empty() {
+                let unit = self.train_list[lead].coupled_units.pop().expect("ERR: NO COUPLED UNITS");
+                self.train_list[lead].cars -= self.train_list[unit].cars;
+                self.train_list[lead].capacity -= self.train_list[unit].capacity;
+                self.train_list[lead].seats -= self.train_list[unit].seats;
+                self.train_list[lead].standing_area -= self.train_list[unit].standing_area;
+                self.train_list[lead].empty_mass -= self.train_list[unit].empty_mass;
+                self.train_list[lead].ready_at = self.time_elapsed + COUPLING_TIME;
+                self.train_list[unit].ready_at = self.time_elapsed + COUPLING_TIME;
+                self.bookkeeping.uncouplings += 1.0;
+                queue.push_back(unit);
+                self.log.write(LogCategory::Train(), LogLevel::Info(), self.time_elapsed, Some(unit), None,
+                    || format!("Train {} UNCOUPLED from train {}", unit, lead));
+            }
+        }
+
+        // Couple anything that is too short to another unit of the same type
+        let mut position = 0;
+        while position < queue.len() {
+            let lead = queue[position];
+            let mut other = position + 1;
+            while other < queue.len() && self.train_list[lead].cars < target {
+                let unit = queue[other];
+                if self.train_list[unit].type_id == self.train_list[lead].type_id && self.train_list[unit].coupled_units.is_empty()
+                        && self.train_list[lead].cars + self.train_list[unit].cars <= target.min(MAX_CONSIST_CARS) {
+                    queue.remove(other);
+                    self.train_list[lead].coupled_units.push(unit);
+                    self.train_list[lead].cars += self.train_list[unit].cars;
+                    self.train_list[lead].capacity += self.train_list[unit].capacity;
+                    self.train_list[lead].seats += self.train_list[unit].seats;
+                    self.train_list[lead].standing_area += self.train_list[unit].standing_area;
+                    self.train_list[lead].empty_mass += self.train_list[unit].empty_mass;
+                    self.train_list[lead].ready_at = self.time_elapsed + COUPLING_TIME;
+                    self.bookkeeping.couplings += 1.0;
+                    self.log.write(LogCategory::Train(), LogLevel::Info(), self.time_elapsed, Some(unit), None,
+                        || format!("Train {} COUPLED onto train {}", unit, lead));
+                } else {
+                    other += 1;
+                }
+            }
+            position += 1;
+        }
+
+        if direction == EASTWARD {self.lines[line_id].east_trains = queue;} else {self.lines[line_id].west_trains = queue;}
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel { // How much detail a log message is, from problems only to every movement
+    Warn(),
+    Info(),
+    Debug(),
+}
+
+#[derive(Clone, Copy)]
+enum LogCategory { // What a log message is about
+    Train(), // Releases, arrivals, departures, holds, failures and coupling
+    Arrival(), // Customers arriving and station closures
+    Customer(), // Customers getting on, off or left behind
+    Full(), // Trains leaving full
+}
+
+const LOG_CATEGORIES : [&str; 4] = ["train", "arrival", "customer", "full"];
+
+impl LogLevel {
+
+    fn from_name(name : &str) -> Option<Option<LogLevel>> {
+        // Some(None) turns a category off
+        return match name.to_lowercase().as_str() {
+            "off" => Some(None),
+            "warn" => Some(Some(LogLevel::Warn())),
+            "info" => Some(Some(LogLevel::Info())),
+            "debug" => Some(Some(LogLevel::Debug())),
+            _ => None,
+        };
+    }
+
+    fn name(&self) -> &str {
+        return match self {
+            LogLevel::Warn() => "WARN",
+            LogLevel::Info() => "INFO",
+            LogLevel::Debug() => "DEBUG",
+        };
+    }
+}
+
+impl LogCategory {
+
+    fn index(&self) -> usize {
+        return match self {
+            LogCategory::Train() => 0,
+            LogCategory::Arrival() => 1,
+            LogCategory::Customer() => 2,
+            LogCategory::Full() => 3,
+        };
+    }
+}
+
+struct Logger { // Decides which debug messages are written during the simulation and where they go
+    levels: [Option<LogLevel>; 4], // Per category, the most detailed level written (None = off)
+    trains: Option<Vec<usize>>, // Only messages about these trains, when given
+    station: Option<String>, // Only messages about this station, when given
+    window: (f32, f32), // Only messages from this part of the day
+    file: Option<std::io::BufWriter<std::fs::File>>, // Messages go to the terminal without one
 }
 
+impl Logger {
+
+    fn off() -> Logger {
+        return Logger {levels : [None; 4], trains : None, station : None, window : (0.0, SIMULATION_LENGTH), file : None};
+    }
+
+    fn is_on(&self) -> bool {
+        return self.levels.iter().any(|level| level.is_some());
+    }
+
+    fn from_spec(spec : &str) -> Result<Logger, String> {
+        // Reads which categories to log at which level, e.g. "train=info,customer=debug"
+        // A level on its own applies to every category and a category on its own is logged at info
+        let mut logger = Logger::off();
+        for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+            let (name, level) = match item.split_once('=') {
+                Some((name, level)) => (name.trim(), LogLevel::from_name(level.trim()).ok_or(format!("Unknown log level: {}", level))?),
+                None => match LogLevel::from_name(item) {
+                    Some(level) => ("all", level),
+                    None => (item, Some(LogLevel::Info())),
+                },
+            };
+            if name == "all" {
+                logger.levels = [level; 4];
+            } else {
+                let category = LOG_CATEGORIES.iter().position(|category| *category == name)
+                    .ok_or(format!("Unknown log category: {} (use {} or all)", name, LOG_CATEGORIES.join(", ")))?;
+                logger.levels[category] = level;
+            }
+        }
+        return Ok(logger);
+    }
+
+    fn wants(&self, category : LogCategory, level : LogLevel, time : f32, train : Option<usize>, station : Option<&str>) -> bool {
+        // Filters only let through messages that are about the filtered train or station
+        if !self.levels[category.index()].is_some_and(|most_detailed| level <= most_detailed) { return false; }
+        if time < self.window.0 || time > self.window.1 { return false; }
+        if let Some(trains) = &self.trains {
+            if !train.is_some_and(|train| trains.contains(&train)) { return false; }
+        }
+        if let Some(name) = &self.station {
+            if station != Some(name.as_str()) { return false; }
+        }
+        return true;
+    }
+
+    fn write(&mut self, category : LogCategory, level : LogLevel, time : f32, train : Option<usize>, station : Option<&str>, message : impl FnOnce() -> String) {
+        // The message is only put together when it is going to be written
+        if !self.wants(category, level, time, train, station) { return; }
+        let line = format!("{} {} {}: {}", format_clock_time(time), level.name(), LOG_CATEGORIES[category.index()], message());
+        match self.file.as_mut() {
+            Some(file) => {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    println!("ERROR: Could not write the log, sending it to the terminal instead: {}", e);
+                    self.file = None;
+                }
+            },
+            None => println!("{}", line),
+        }
+    }
+}
 
+#[derive(Clone, Copy)]
 enum EventTypes {
     TrainArrival(usize, usize), // TRAIN ID, STATION ID
     TrainDeparture(usize, usize), // TRAIN ID, NEXT STATION ID
-    TrainRelease(i8), // TRAVEL DIRECTION
-    CustomerArrival(usize), // STATION ID
+    TrainRelease(usize, i8), // LINE ID, TRAVEL DIRECTION
+    ScheduledRelease(usize, i8, usize), // LINE ID, TRAVEL DIRECTION, DEPARTURE INDEX IN THE LINE'S TIMETABLE
+    CustomerArrival(usize), // NETWORK STATION ID
+    TrainFailure(usize, u32), // TRAIN ID, TRIP NUMBER (failures from an earlier trip are ignored)
+    TrainRescue(usize), // TRAIN ID
+    StationClosure(usize, usize), // LINE ID, STATION ID, clears out the platforms as the station closes
     PollStats(), // Polls for customers waiting and other stats, should be called every minute on the minute
     Dummy(), // DOES NOTHING
 }
@@ -127,11 +1200,13 @@ enum EventTypes {
 struct DiscreteEvent {
     event : EventTypes,
     time : f32,
+    sequence : u64,
 }
 
 impl Ord for DiscreteEvent {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.time.total_cmp(&self.time)
+        // Ties are broken first in first out so every RNG stream sees the same order of events between runs
+        other.time.total_cmp(&self.time).then(other.sequence.cmp(&self.sequence))
     }
 }
 
@@ -145,7 +1220,7 @@ impl Eq for DiscreteEvent {}
 
 impl PartialEq for DiscreteEvent {
     fn eq(&self, other: &Self) -> bool {
-        (self.time) == (other.time)
+        (self.time) == (other.time) && (self.sequence) == (other.sequence)
     }
 }
 
@@ -166,53 +1241,595 @@ struct Bookkeeper {
     max_customers_waiting: f32,
     max_customers_waiting_t: f32,
     average_trains_deployed : f32,
+    max_trains_deployed : f32,
+    train_km: f32,
+    car_km: f32,
+    energy_kwh: f32,
+    passenger_km: f32,
+    hourly_energy: [f32; HOURS_TRACKED],
+    segment_energy: Vec<f32>, // Per pair of neighbouring network stations [lower id * od_stations + higher id], both directions together
+    couplings: f32,
+    uncouplings: f32,
+    scheduled_departures: f32, // Timetabled departures that came due
+    late_departures: f32,
+    cancelled_departures: f32,
+    departure_lateness: f32, // Minutes late summed over the late departures
+    max_departure_lateness: f32,
+    incidents: f32,
+    trains_recovered: f32,
+    trains_pulled: f32,
+    incident_delay_minutes: f32, // Passenger minutes lost to failed trains and the trains stuck behind them
+    customers_diverted: f32,
+    customers_lost_to_closures: f32,
+    customers_rerouted: f32, // Got off a stop early because the station they were changing trains at was closed, and found another way
+    single_track_holds: f32,
+    short_turns: f32,
+    transfers: f32,
+    interlined_holds: f32,
+    planned_trip_cost: f32, // Generalized minutes of the routes customers picked when they arrived
+    customers_off_cheapest_route: f32,
+    customers_balked: f32, // Lost ridership: left on arrival because the platform was too crowded, or gave up waiting
+    customers_reneged: f32,
+    reneged_waiting_time: f32,
+    station_transfers: Vec<f32>, // Per network station, customers who changed lines there and how long they waited for the next train
+    station_transfer_wait: Vec<f32>,
+    car_load_samples: f32, // Departures with customers on board, used to average the car loading stats
+    busiest_car_ratio: f32, // Summed load of the busiest car over the average car load
+    uneven_departures: f32, // Departures with a full car while another car was less than half full
+    car_position_riders: [f32; CAR_POSITION_BUCKETS], // Customers on board by section of the train, west end first
+    customers_held_at_gates: f32,
+    gate_hold_time: f32,
+    concourse_length_total: Vec<f32>, // Per network station, summed every minute for the average
+    concourse_length_max: Vec<f32>,
+    platform_full_minutes: Vec<f32>, // Per network station, platform-minutes at or above capacity
+    platform_over_minutes: Vec<f32>, // Per network station, platform-minutes above capacity (transfers and detrained customers don't go through the gates)
+    crowding_minutes: [f32; 4], // Passenger-minutes spent riding seated, standing comfortably, crowded and crush loaded
+    left_behind: Vec<f32>, // Per network station and hour [station * HOURS_TRACKED + hour], customers a full train had to leave behind
+    boarded_after_pass_ups: Vec<[f32; 4]>, // Per network station and hour [station * HOURS_TRACKED + hour], customers boarding after being passed up 0, 1, 2 and 3+ times
+    od_stations: usize, // Per origin-destination pair totals, indexed [origin * od_stations + destination]
+    od_trips: Vec<f32>,
+    od_wait: Vec<f32>,
+    od_ride: Vec<f32>,
+    type_names: Vec<String>, // Per train type stats, filled in once the simulation is over
+    type_trains: Vec<f32>,
+    type_util_percent: Vec<f32>,
+    type_full_percent: Vec<f32>,
+    type_energy: Vec<f32>, // kWh per train
+    max_train_energy: f32,
+    train_util_percent: Vec<f32>, // Per train, average utilization over the day
+    waiting_by_minute: Vec<f32>, // Customers waiting, sampled every minute
+    station_wait: Vec<f32>, // Per network station, minutes waited by customers boarding there
+    segment_load: Vec<f32>, // Per FROM, TO pair of neighbouring network stations [from * od_stations + to], customers carried over the day
 }
 
 impl Bookkeeper {
 
-    fn new() -> Bookkeeper {
-        // Returns a BookKeeper class with all stats properly initalized
-        return Bookkeeper {total_customers : 0.0, total_customers_boarded: 0.0, 
-            total_customers_departed: 0.0, total_station_waiting_time: 0.0, 
-            max_station_waiting_time: 0.0, max_station_waiting_time_t: 0.0, average_train_util_percent: 0.0, 
-            total_trains : 0.0, max_train_util_percent: 0.0, time_train_full_percent: 0.0,
-            currently_waiting_customers : 0.0, average_customers_waiting: 0.0, max_customers_waiting : 0.0, 
-            max_customers_waiting_t : 0.0, average_trains_deployed : 0.0};
+    fn track_od_pairs(&mut self, stations : usize) {
+        self.od_stations = stations;
+        self.od_trips = vec![0.0; stations * stations];
+        self.od_wait = vec![0.0; stations * stations];
+        self.od_ride = vec![0.0; stations * stations];
+        self.segment_energy = vec![0.0; stations * stations];
+        self.segment_load = vec![0.0; stations * stations];
+        self.station_wait = vec![0.0; stations];
+        self.station_transfers = vec![0.0; stations];
+        self.station_transfer_wait = vec![0.0; stations];
+        self.left_behind = vec![0.0; stations * HOURS_TRACKED];
+        self.concourse_length_total = vec![0.0; stations];
+        self.concourse_length_max = vec![0.0; stations];
+        self.platform_full_minutes = vec![0.0; stations];
+        self.platform_over_minutes = vec![0.0; stations];
+        self.boarded_after_pass_ups = vec![[0.0; 4]; stations * HOURS_TRACKED];
     }
 
-    fn generate_report(&self, title : String) {
-        // Prints a report made out of interal stats to the terminal
-        println!("{}\n", title);
-        println!("Customers:");
-        print!("    TOTAL CUSTOMERS BOARDED / DEPARTED / GENERATED: {} / {} / {}\n", self.total_customers_boarded, self.total_customers_departed, self.total_customers);
-        print!("    AVERAGE WAIT TIME: {:.2}\n", self.total_station_waiting_time / self.total_customers_boarded);
-        print!("    MAXIMUM WAIT TIME: {:.2} @ minute {}\n", self.max_station_waiting_time, self.max_station_waiting_time_t);
-        print!("    AVERAGE CUSTOMERS WAITING: {:.2}\n", self.average_customers_waiting);
-        print!("    MAXIMUM CUSTOMERS WAITING: {:.2} @ minute {}\n", self.max_customers_waiting, self.max_customers_waiting_t);
-        print!("    AVERAGE THROUGHPUT (customers/hour): {:.2}\n", self.total_customers_departed / 20.0); // Customers per hour
+    fn record_car_loads(&mut self, car_riders : &[f32], car_capacity : f32) {
+        // Adds a departing train's loading to the car imbalance stats
+        let cars = car_riders.len();
+        let total : f32 = car_riders.iter().sum();
+        let busiest = car_riders.iter().cloned().fold(0.0, f32::max);
+        let quietest = car_riders.iter().cloned().fold(f32::INFINITY, f32::min);
+        self.car_load_samples += 1.0;
+        self.busiest_car_ratio += busiest / (total / cars as f32);
+        if busiest >= car_capacity && quietest < car_capacity / 2.0 {
+            self.uneven_departures += 1.0;
+        }
+        // Each car's riders are spread over the sections of the train it covers, by how much of the car is in each
+        for car in 0..cars {
+            let car_start = car as f32 / cars as f32;
+            let car_end = (car + 1) as f32 / cars as f32;
+            for bucket in 0..CAR_POSITION_BUCKETS {
+                let bucket_start = bucket as f32 / CAR_POSITION_BUCKETS as f32;
+                let bucket_end = (bucket + 1) as f32 / CAR_POSITION_BUCKETS as f32;
+                let overlap = car_end.min(bucket_end) - car_start.max(bucket_start);
+                if overlap > 0.0 {
+                    self.car_position_riders[bucket] += car_riders[car] * overlap * cars as f32;
+                }
+            }
+        }
+    }
 
-        println!("\nTrain Usage:");
-        print!("    AVERAGE PERCENT OF TRAINS DEPLOYED (total={}): {:.2}%\n", NUMBER_OF_TRAINS, self.average_trains_deployed);
-        print!("    AVERAGE TRAIN UTILIZATION: {:.2}%\n", self.average_train_util_percent);
-        print!("    MAXIMUM TRAIN UTILIZATION: {:.2}%\n", self.max_train_util_percent);
-        print!("    PERCENT OF TIME TRAINS ARE FULL FOR: {:.2}%\n", self.time_train_full_percent);
+    fn record_crowding(&mut self, riders : f32, seats : f32, standing_area : f32, minutes : f32) {
+        // Splits the passenger-minutes of a ride between stations into comfort bands
+        let seated = riders.min(seats);
+        let standing = riders - seated;
+        self.crowding_minutes[0] += seated * minutes;
+        self.crowding_minutes[standing_band(standing, standing_area)] += standing * minutes;
     }
 
-}
+    fn record_scheduled_departure(&mut self, lateness : Option<f32>) {
+        // Lateness is None when the departure had to be cancelled
+        self.scheduled_departures += 1.0;
+        match lateness {
+            Some(minutes) if minutes > LATE_DEPARTURE_TOLERANCE => {
+                self.late_departures += 1.0;
+                self.departure_lateness += minutes;
+                self.max_departure_lateness = self.max_departure_lateness.max(minutes);
+            },
+            Some(_) => (),
+            None => self.cancelled_departures += 1.0,
+        }
+    }
 
-struct Station {
+    fn record_pass_ups(&mut self, station_id : usize, time : f32, times_left_behind : u32) {
+        // Adds a boarding customer to the station's pass-up distribution for the hour they boarded in
+        let bucket = (times_left_behind as usize).min(3);
+        let hour = ((time / 60.0).floor() as usize).min(HOURS_TRACKED - 1);
+        self.boarded_after_pass_ups[station_id * HOURS_TRACKED + hour][bucket] += 1.0;
+    }
+
+    fn station_pass_ups(&self, station_id : usize) -> [f32; 4] {
+        // A station's pass-up distribution over the whole day
+        let mut pass_ups = [0.0; 4];
+        for hour in 0..HOURS_TRACKED {
+            for bucket in 0..4 {
+                pass_ups[bucket] += self.boarded_after_pass_ups[station_id * HOURS_TRACKED + hour][bucket];
+            }
+        }
+        return pass_ups;
+    }
+
+    fn record_trip(&mut self, customer : &Customer) {
+        // Adds a finished trip to its origin-destination pair
+        let pair = customer.origin * self.od_stations + customer.destination;
+        self.od_trips[pair] += 1.0;
+        self.od_wait[pair] += customer.waited;
+        self.od_ride[pair] += customer.rode;
+    }
+
+    fn print_stopping_pattern_impact(&self, baseline : &Bookkeeper, network : &Network, od_report_path : Option<&String>) {
+        // Compares a run with skip-stop or express trains against an all stops run with the same seed, per origin-destination pair
+        // Only pairs with enough trips in both runs are compared
+        let min_trips = 20.0;
+        let mut changes: Vec<(f32, usize)> = Vec::new(); // MINUTES SAVED PER TRIP, PAIR
+        let mut total_trips = 0.0;
+        let mut wait_added = 0.0;
+        let mut ride_saved = 0.0;
+        let mut csv = String::from("origin,destination,trips,all_stops_wait,wait,all_stops_ride,ride,minutes_saved\n");
+
+        for pair in 0..self.od_trips.len() {
+            if self.od_trips[pair] < min_trips || baseline.od_trips[pair] < min_trips { continue; }
+            let wait = self.od_wait[pair] / self.od_trips[pair];
+            let ride = self.od_ride[pair] / self.od_trips[pair];
+            let baseline_wait = baseline.od_wait[pair] / baseline.od_trips[pair];
+            let baseline_ride = baseline.od_ride[pair] / baseline.od_trips[pair];
+            let saved = (baseline_wait + baseline_ride) - (wait + ride);
+
+            total_trips += self.od_trips[pair];
+            wait_added += (wait - baseline_wait) * self.od_trips[pair];
+            ride_saved += (baseline_ride - ride) * self.od_trips[pair];
+            changes.push((saved, pair));
+            csv += &format!("{},{},{},{:.3},{:.3},{:.3},{:.3},{:.3}\n", network.id_to_name(pair / self.od_stations), network.id_to_name(pair % self.od_stations),
+                self.od_trips[pair], baseline_wait, wait, baseline_ride, ride, saved);
+        }
+        changes.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        println!("\nStopping Pattern Impact (vs. all stops run with the same seed):");
+        print!("    CUSTOMERS WHO HAD TO TRANSFER: {}\n", self.transfers);
+        if total_trips > 0.0 {
+            print!("    AVERAGE RIDE TIME SAVED PER TRIP: {:.2}\n", ride_saved / total_trips);
+            print!("    AVERAGE WAIT TIME ADDED PER TRIP: {:.2}\n", wait_added / total_trips);
+        } else {
+            print!("    NO ORIGIN-DESTINATION PAIR HAD {} TRIPS IN BOTH RUNS TO COMPARE\n", min_trips);
+        }
+        print!("    ORIGIN-DESTINATION PAIRS FASTER / SLOWER: {} / {}\n", changes.iter().filter(|c| c.0 > 0.0).count(), changes.iter().filter(|c| c.0 < 0.0).count());
+        let shown = changes.len().min(5);
+        println!("    BIGGEST GAINS (minutes saved per trip):");
+        for (saved, pair) in &changes[..shown] {
+            print!("        {} -> {}: {:+.2}\n", network.id_to_name(pair / self.od_stations), network.id_to_name(pair % self.od_stations), saved);
+        }
+        println!("    BIGGEST LOSSES (minutes saved per trip):");
+        for (saved, pair) in changes[changes.len() - shown..].iter().rev() {
+            print!("        {} -> {}: {:+.2}\n", network.id_to_name(pair / self.od_stations), network.id_to_name(pair % self.od_stations), saved);
+        }
+
+        if let Some(path) = od_report_path {
+            match std::fs::write(path, csv) {
+                Ok(()) => println!("    Origin-destination comparison written to {}", path),
+                Err(e) => println!("ERROR: Could not write {}: {}", path, e),
+            }
+        }
+    }
+
+    fn print_disruption_impact(&self, baseline : &Bookkeeper) {
+        // Compares a disrupted run against an undisrupted run with the same seed
+        let average_wait = self.total_station_waiting_time / self.total_customers_boarded;
+        let baseline_average_wait = baseline.total_station_waiting_time / baseline.total_customers_boarded;
+
+        println!("\nDisruption Impact (vs. undisrupted run with the same seed):");
+        print!("    AVERAGE WAIT TIME: {:.2} -> {:.2} ({:+.2})\n", baseline_average_wait, average_wait, average_wait - baseline_average_wait);
+        print!("    MAXIMUM WAIT TIME: {:.2} -> {:.2} ({:+.2})\n", baseline.max_station_waiting_time, self.max_station_waiting_time,
+            self.max_station_waiting_time - baseline.max_station_waiting_time);
+        print!("    TOTAL WAITING TIME (passenger-minutes): {:.0} -> {:.0} ({:+.0})\n", baseline.total_station_waiting_time, self.total_station_waiting_time,
+            self.total_station_waiting_time - baseline.total_station_waiting_time);
+        print!("    CUSTOMERS DELIVERED: {} -> {} ({:+})\n", baseline.total_customers_departed, self.total_customers_departed,
+            self.total_customers_departed - baseline.total_customers_departed);
+        print!("    AVERAGE CUSTOMERS WAITING: {:.2} -> {:.2} ({:+.2})\n", baseline.average_customers_waiting, self.average_customers_waiting,
+            self.average_customers_waiting - baseline.average_customers_waiting);
+    }
+
+    fn new() -> Bookkeeper {
+        // Returns a BookKeeper class with all stats properly initalized
+        return Bookkeeper {total_customers : 0.0, total_customers_boarded: 0.0, 
+            total_customers_departed: 0.0, total_station_waiting_time: 0.0, 
+            max_station_waiting_time: 0.0, max_station_waiting_time_t: 0.0, average_train_util_percent: 0.0, 
+            total_trains : 0.0, max_train_util_percent: 0.0, time_train_full_percent: 0.0,
+            currently_waiting_customers : 0.0, average_customers_waiting: 0.0, max_customers_waiting : 0.0, 
+            max_customers_waiting_t : 0.0, average_trains_deployed : 0.0, max_trains_deployed : 0.0, train_km : 0.0, car_km : 0.0,
+            energy_kwh : 0.0, passenger_km : 0.0, hourly_energy : [0.0; HOURS_TRACKED], segment_energy : Vec::new(), couplings : 0.0, uncouplings : 0.0,
+            scheduled_departures : 0.0, late_departures : 0.0, cancelled_departures : 0.0, departure_lateness : 0.0, max_departure_lateness : 0.0,
+            incidents : 0.0, trains_recovered : 0.0, trains_pulled : 0.0, incident_delay_minutes : 0.0,
+            customers_diverted : 0.0, customers_lost_to_closures : 0.0, customers_rerouted : 0.0, single_track_holds : 0.0, short_turns : 0.0, transfers : 0.0,
+            interlined_holds : 0.0, planned_trip_cost : 0.0, customers_off_cheapest_route : 0.0,
+            customers_balked : 0.0, customers_reneged : 0.0, reneged_waiting_time : 0.0, station_transfers : Vec::new(), station_transfer_wait : Vec::new(),
+            car_load_samples : 0.0, busiest_car_ratio : 0.0, uneven_departures : 0.0, car_position_riders : [0.0; CAR_POSITION_BUCKETS],
+            customers_held_at_gates : 0.0, gate_hold_time : 0.0, concourse_length_total : Vec::new(), concourse_length_max : Vec::new(),
+            platform_full_minutes : Vec::new(), platform_over_minutes : Vec::new(), crowding_minutes : [0.0; 4], left_behind : Vec::new(), boarded_after_pass_ups : Vec::new(), od_stations : 0, od_trips : Vec::new(), od_wait : Vec::new(), od_ride : Vec::new(),
+            type_names : Vec::new(), type_trains : Vec::new(), type_util_percent : Vec::new(), type_full_percent : Vec::new(),
+            type_energy : Vec::new(), max_train_energy : 0.0, train_util_percent : Vec::new(), waiting_by_minute : Vec::new(),
+            station_wait : Vec::new(), segment_load : Vec::new()};
+    }
+
+    fn crowding_weighted_minutes(&self) -> f32 {
+        // Passenger-minutes on board, with crowded minutes counting for more
+        let mut weighted_minutes = 0.0;
+        for band in 0..4 {
+            weighted_minutes += self.crowding_minutes[band] * CROWDING_MULTIPLIERS[band];
+        }
+        return weighted_minutes;
+    }
+
+    fn train_hours(&self) -> (f32, f32) {
+        // Hours trains spent in service and on standby over the day
+        let in_service = self.average_trains_deployed * SIMULATION_LENGTH / 60.0;
+        return (in_service, (self.total_trains * SIMULATION_LENGTH / 60.0) - in_service);
+    }
+
+    fn operating_cost(&self, costs : &CostModel) -> f32 {
+        let (in_service, standby) = self.train_hours();
+        return (in_service * costs.train_hour) + (standby * costs.standby_hour) + (self.car_km * costs.car_km) + (self.energy_kwh * costs.kwh);
+    }
+
+    fn passenger_cost(&self, costs : &CostModel) -> f32 {
+        let waiting = self.total_station_waiting_time + self.gate_hold_time;
+        let lost = self.customers_balked + self.customers_reneged + self.customers_lost_to_closures;
+        return (waiting * costs.wait_minute) + (self.crowding_weighted_minutes() * costs.ride_minute) + (lost * costs.lost_trip);
+    }
+
+    fn social_cost(&self, costs : &CostModel) -> f32 {
+        // What the day cost the operator and the customers together, used to compare runs on one number
+        return self.operating_cost(costs) + self.passenger_cost(costs);
+    }
+
+    fn generate_report(&self, title : String, network : &Network, costs : &CostModel) {
+        // Prints a report made out of interal stats to the terminal
+        println!("{}\n", title);
+        println!("Customers:");
+        print!("    TOTAL CUSTOMERS BOARDED / DEPARTED / GENERATED: {} / {} / {}\n", self.total_customers_boarded, self.total_customers_departed, self.total_customers);
+        print!("    AVERAGE WAIT TIME: {:.2}\n", self.total_station_waiting_time / self.total_customers_boarded);
+        print!("    MAXIMUM WAIT TIME: {:.2} @ minute {}\n", self.max_station_waiting_time, self.max_station_waiting_time_t);
+        print!("    AVERAGE CUSTOMERS WAITING: {:.2}\n", self.average_customers_waiting);
+        print!("    MAXIMUM CUSTOMERS WAITING: {:.2} @ minute {}\n", self.max_customers_waiting, self.max_customers_waiting_t);
+        print!("    AVERAGE THROUGHPUT (customers/hour): {:.2}\n", self.total_customers_departed / 20.0); // Customers per hour
+
+        if self.customers_balked + self.customers_reneged > 0.0 {
+            println!("\nLost Ridership:");
+            print!("    CUSTOMERS WHO BALKED / RENEGED: {} / {}\n", self.customers_balked, self.customers_reneged);
+            print!("    PERCENT OF CUSTOMERS LOST: {:.2}%\n", (self.customers_balked + self.customers_reneged) * 100.0 / self.total_customers);
+            if self.customers_reneged > 0.0 {
+                print!("    AVERAGE WAIT BEFORE RENEGING: {:.2}\n", self.reneged_waiting_time / self.customers_reneged);
+            }
+        }
+
+        println!("\nTrain Usage:");
+        print!("    AVERAGE PERCENT OF TRAINS DEPLOYED (total={}): {:.2}%\n", self.total_trains, self.average_trains_deployed);
+        print!("    MAXIMUM TRAINS DEPLOYED AT ONCE: {}\n", self.max_trains_deployed);
+        print!("    AVERAGE TRAIN UTILIZATION: {:.2}%\n", self.average_train_util_percent);
+        print!("    MAXIMUM TRAIN UTILIZATION: {:.2}%\n", self.max_train_util_percent);
+        print!("    PERCENT OF TIME TRAINS ARE FULL FOR: {:.2}%\n", self.time_train_full_percent);
+
+        let riding_minutes : f32 = self.crowding_minutes.iter().sum();
+        let weighted_minutes = self.crowding_weighted_minutes();
+        let band_percent = |band : usize| share(self.crowding_minutes[band] * 100.0, riding_minutes);
+        println!("\nCrowding:");
+        print!("    PASSENGER-MINUTES SEATED / COMFORTABLE STANDING / CROWDED / CRUSH: {:.2}% / {:.2}% / {:.2}% / {:.2}%\n",
+            band_percent(0), band_percent(1), band_percent(2), band_percent(3));
+        print!("    IN-VEHICLE MINUTES PER TRIP (ACTUAL / CROWDING-WEIGHTED): {:.2} / {:.2}\n",
+            share(riding_minutes, self.total_customers_departed), share(weighted_minutes, self.total_customers_departed));
+        print!("    GENERALIZED TRIP TIME (WAIT + CROWDING-WEIGHTED RIDE): {:.2}\n",
+            share(self.total_station_waiting_time + weighted_minutes, self.total_customers_departed));
+
+        let position_total : f32 = self.car_position_riders.iter().sum();
+        let position_shares: Vec<String> = self.car_position_riders.iter().map(|riders| format!("{:.2}%", share(riders * 100.0, position_total))).collect();
+        println!("\nCar Loading:");
+        print!("    BUSIEST CAR LOAD / AVERAGE CAR LOAD: {:.2}\n", share(self.busiest_car_ratio, self.car_load_samples));
+        print!("    DEPARTURES WITH A FULL CAR WHILE ANOTHER WAS UNDER HALF FULL: {:.2}%\n", share(self.uneven_departures * 100.0, self.car_load_samples));
+        print!("    CUSTOMERS BY SECTION OF THE TRAIN (WEST END FIRST): {}\n", position_shares.join(" / "));
+
+        println!("\nOperations:");
+        print!("    TRAIN-KM / CAR-KM: {:.1} / {:.1}\n", self.train_km, self.car_km);
+        print!("    AVERAGE CARS PER TRAIN: {:.2}\n", share(self.car_km, self.train_km));
+        print!("    COUPLINGS / UNCOUPLINGS: {} / {}\n", self.couplings, self.uncouplings);
+        print!("    SHORT TURNS: {}\n", self.short_turns);
+
+        println!("\nEnergy:");
+        print!("    TOTAL ENERGY (kWh): {:.1}\n", self.energy_kwh);
+        print!("    kWh PER TRAIN-KM / PER CAR-KM: {:.2} / {:.2}\n", share(self.energy_kwh, self.train_km), share(self.energy_kwh, self.car_km));
+        print!("    kWh PER PASSENGER-KM: {:.3}\n", share(self.energy_kwh, self.passenger_km));
+        print!("    AVERAGE / MAXIMUM kWh PER TRAIN: {:.1} / {:.1}\n", share(self.energy_kwh, self.total_trains), self.max_train_energy);
+        let mut by_hour = Vec::new();
+        for hour in 0..HOURS_TRACKED {
+            if self.hourly_energy[hour] > 0.0 { by_hour.push(format!("{} {:.0}", hour_label(hour), self.hourly_energy[hour])); }
+        }
+        print!("    kWh BY HOUR: {}\n", by_hour.join(", "));
+        for a in 0..self.od_stations {
+            for b in a + 1..self.od_stations {
+                let kwh = self.segment_energy[a * self.od_stations + b];
+                if kwh == 0.0 { continue; }
+                print!("    {} - {}: {:.1} kWh\n", network.id_to_name(a), network.id_to_name(b), kwh);
+            }
+        }
+
+        let (in_service, standby) = self.train_hours();
+        let waiting = self.total_station_waiting_time + self.gate_hold_time;
+        let lost = self.customers_balked + self.customers_reneged + self.customers_lost_to_closures;
+        println!("\nCosts:");
+        print!("    TRAIN-HOURS IN SERVICE / STANDBY: {:.1} / {:.1}\n", in_service, standby);
+        print!("    IN SERVICE / STANDBY / CAR-KM / ENERGY: ${:.0} / ${:.0} / ${:.0} / ${:.0}\n", in_service * costs.train_hour,
+            standby * costs.standby_hour, self.car_km * costs.car_km, self.energy_kwh * costs.kwh);
+        print!("    WAITING / RIDING / LOST TRIPS: ${:.0} / ${:.0} / ${:.0}\n", waiting * costs.wait_minute,
+            weighted_minutes * costs.ride_minute, lost * costs.lost_trip);
+        print!("    OPERATING / PASSENGER COST: ${:.0} / ${:.0}\n", self.operating_cost(costs), self.passenger_cost(costs));
+        print!("    SOCIAL COST: ${:.0} (${:.2} PER CUSTOMER)\n", self.social_cost(costs), self.social_cost(costs) / self.total_customers);
+
+        if self.scheduled_departures > 0.0 {
+            let on_time = self.scheduled_departures - self.late_departures - self.cancelled_departures;
+            println!("\nTimetable:");
+            print!("    SCHEDULED DEPARTURES: {}\n", self.scheduled_departures);
+            print!("    ON TIME / LATE / CANCELLED: {} / {} / {}\n", on_time, self.late_departures, self.cancelled_departures);
+            print!("    PERCENT ON TIME (within {} minute): {:.2}%\n", LATE_DEPARTURE_TOLERANCE, on_time * 100.0 / self.scheduled_departures);
+            if self.late_departures > 0.0 {
+                print!("    AVERAGE / MAXIMUM LATENESS OF LATE DEPARTURES: {:.2} / {:.2}\n", self.departure_lateness / self.late_departures, self.max_departure_lateness);
+            }
+        }
+
+        if self.incidents > 0.0 {
+            println!("\nIncidents:");
+            print!("    TRAIN FAILURES (RECOVERED / PULLED FROM SERVICE): {} ({} / {})\n", self.incidents, self.trains_recovered, self.trains_pulled);
+            print!("    PASSENGER DELAY CAUSED BY INCIDENTS (passenger-minutes): {:.1}\n", self.incident_delay_minutes);
+        }
+
+        if self.customers_diverted + self.customers_lost_to_closures + self.customers_rerouted + self.single_track_holds > 0.0 {
+            println!("\nDisruptions:");
+            print!("    CUSTOMERS DIVERTED / LOST TO STATION CLOSURES: {} / {}\n", self.customers_diverted, self.customers_lost_to_closures);
+            print!("    CUSTOMERS REROUTED AROUND A CLOSED TRANSFER STATION: {}\n", self.customers_rerouted);
+            print!("    TRAINS HELD FOR SINGLE TRACKING: {}\n", self.single_track_holds);
+        }
+
+        let platform_full_minutes : f32 = self.platform_full_minutes.iter().sum();
+        if platform_full_minutes + self.customers_held_at_gates > 0.0 {
+            println!("\nPlatform Capacity:");
+            print!("    CUSTOMERS HELD AT THE FAREGATES: {}\n", self.customers_held_at_gates);
+            print!("    AVERAGE TIME HELD: {:.2}\n", self.gate_hold_time / self.customers_held_at_gates.max(1.0));
+            for station_id in 0..self.od_stations {
+                if self.platform_full_minutes[station_id] + self.concourse_length_max[station_id] == 0.0 { continue; }
+                print!("    {}: PLATFORMS FULL / OVER CAPACITY {} / {} MINUTES, CONCOURSE QUEUE AVERAGE {:.2} / MAXIMUM {}\n",
+                    network.id_to_name(station_id), self.platform_full_minutes[station_id], self.platform_over_minutes[station_id],
+                    self.concourse_length_total[station_id] / SIMULATION_LENGTH, self.concourse_length_max[station_id]);
+            }
+        }
+
+        let total_left_behind : f32 = self.left_behind.iter().sum();
+        if total_left_behind > 0.0 {
+            println!("\nDenied Boarding:");
+            print!("    TIMES A CUSTOMER WAS LEFT BEHIND BY A FULL TRAIN: {}\n", total_left_behind);
+            let mut by_hour = Vec::new();
+            for hour in 0..HOURS_TRACKED {
+                let hour_total : f32 = (0..self.od_stations).map(|station_id| self.left_behind[station_id * HOURS_TRACKED + hour]).sum();
+                if hour_total > 0.0 { by_hour.push(format!("{} {}", hour_label(hour), hour_total)); }
+            }
+            print!("    BY HOUR: {}\n", by_hour.join(", "));
+            for station_id in 0..self.od_stations {
+                let by_hour = &self.left_behind[station_id * HOURS_TRACKED..(station_id + 1) * HOURS_TRACKED];
+                let station_total : f32 = by_hour.iter().sum();
+                if station_total == 0.0 { continue; }
+                let mut worst_hour = 0;
+                for hour in 0..HOURS_TRACKED {
+                    if by_hour[hour] > by_hour[worst_hour] { worst_hour = hour; }
+                }
+                let pass_ups = self.station_pass_ups(station_id);
+                print!("    {}: {} LEFT BEHIND (WORST HOUR {}, {}), BOARDED AFTER 0 / 1 / 2 / 3+ PASS-UPS: {} / {} / {} / {}\n",
+                    network.id_to_name(station_id), station_total, hour_label(worst_hour), by_hour[worst_hour], pass_ups[0], pass_ups[1], pass_ups[2], pass_ups[3]);
+                for hour in 0..HOURS_TRACKED {
+                    let pass_ups = self.boarded_after_pass_ups[station_id * HOURS_TRACKED + hour];
+                    if pass_ups[1] + pass_ups[2] + pass_ups[3] == 0.0 { continue; }
+                    print!("        {}: BOARDED AFTER 0 / 1 / 2 / 3+ PASS-UPS: {} / {} / {} / {}\n", hour_label(hour), pass_ups[0], pass_ups[1], pass_ups[2], pass_ups[3]);
+                }
+            }
+        }
+
+        if network.is_multi_line() {
+            println!("\nTransfers:");
+            print!("    TRAINS HELD FOR INTERLINED TRACK: {}\n", self.interlined_holds);
+            print!("    AVERAGE PLANNED TRIP COST (generalized minutes): {:.2}\n", self.planned_trip_cost / self.total_customers);
+            print!("    CUSTOMERS NOT TAKING THE CHEAPEST ROUTE: {}\n", self.customers_off_cheapest_route);
+            for station_id in 0..self.station_transfers.len() {
+                if self.station_transfers[station_id] == 0.0 { continue; }
+                print!("    {}: {} LINE CHANGES, {:.2} AVERAGE WAIT FOR THE NEXT TRAIN\n", network.id_to_name(station_id),
+                    self.station_transfers[station_id], self.station_transfer_wait[station_id] / self.station_transfers[station_id]);
+            }
+        }
+
+        println!("\nTrain Usage By Type:");
+        for i in 0..self.type_names.len() {
+            print!("    {} (x{}): {:.2}% AVERAGE UTILIZATION, FULL {:.2}% OF THE TIME, {:.1} kWh PER TRAIN\n", self.type_names[i], self.type_trains[i],
+                self.type_util_percent[i], self.type_full_percent[i], self.type_energy[i]);
+        }
+    }
+
+    fn write_html_report(&self, path : &str, title : &str, network : &Network, lines : &[Line], costs : &CostModel) -> Result<(), String> {
+        // Writes the main results as a single HTML page, the charts are inline SVG so it opens without a connection
+        let riding_minutes : f32 = self.crowding_minutes.iter().sum();
+        let (in_service, standby) = self.train_hours();
+        let lost = self.customers_balked + self.customers_reneged + self.customers_lost_to_closures;
+        let summary = [
+            ("Customers generated", format!("{}", self.total_customers)),
+            ("Customers boarded / delivered", format!("{} / {}", self.total_customers_boarded, self.total_customers_departed)),
+            ("Average wait (minutes)", format!("{:.2}", self.total_station_waiting_time / self.total_customers_boarded)),
+            ("Maximum wait (minutes)", format!("{:.2} at {}", self.max_station_waiting_time, format_clock_time(self.max_station_waiting_time_t))),
+            ("Average / maximum customers waiting", format!("{:.2} / {}", self.average_customers_waiting, self.max_customers_waiting)),
+            ("Customers lost", format!("{}", lost)),
+            ("In-vehicle minutes per trip", format!("{:.2}", share(riding_minutes, self.total_customers_departed))),
+            ("Crowding-weighted minutes per trip", format!("{:.2}", share(self.crowding_weighted_minutes(), self.total_customers_departed))),
+            ("Trains in the fleet / most deployed at once", format!("{} / {}", self.total_trains, self.max_trains_deployed)),
+            ("Average / maximum train utilization", format!("{:.2}% / {:.2}%", self.average_train_util_percent, self.max_train_util_percent)),
+            ("Train-km / car-km", format!("{:.1} / {:.1}", self.train_km, self.car_km)),
+            ("Train-hours in service / standby", format!("{:.1} / {:.1}", in_service, standby)),
+            ("Energy (kWh)", format!("{:.1}", self.energy_kwh)),
+            ("Operating / passenger cost", format!("${:.0} / ${:.0}", self.operating_cost(costs), self.passenger_cost(costs))),
+            ("Social cost", format!("${:.0} (${:.2} per customer)", self.social_cost(costs), self.social_cost(costs) / self.total_customers)),
+        ];
+
+        let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n", xml_escape(title));
+        html += "<style>body{font-family:sans-serif;margin:2em;max-width:1000px}table{border-collapse:collapse;margin-bottom:2em}";
+        html += "td,th{border:1px solid #ccc;padding:4px 10px;text-align:right}td:first-child,th:first-child{text-align:left}h2{margin-top:1.5em}</style>\n";
+        html += &format!("</head>\n<body>\n<h1>{}</h1>\n", xml_escape(title));
+
+        html += "<h2>Summary</h2>\n<table>\n";
+        for (label, value) in summary.iter() {
+            html += &format!("<tr><td>{}</td><td>{}</td></tr>\n", label, xml_escape(value));
+        }
+        html += "</table>\n";
+
+        html += "<h2>Customers Waiting</h2>\n";
+        html += &svg_line_chart(&self.waiting_by_minute, "Customers waiting");
+
+        let mut station_names = Vec::new();
+        let mut average_waits = Vec::new();
+        html += "<h2>Stations</h2>\n<table>\n<tr><th>Station</th><th>Boardings</th><th>Average wait</th><th>Left behind</th><th>Line changes</th></tr>\n";
+        for station_id in 0..self.od_stations {
+            let boardings : f32 = self.station_pass_ups(station_id).iter().sum();
+            let left_behind : f32 = self.left_behind[station_id * HOURS_TRACKED..(station_id + 1) * HOURS_TRACKED].iter().sum();
+            let average_wait = if boardings > 0.0 {self.station_wait[station_id] / boardings} else {0.0};
+            html += &format!("<tr><td>{}</td><td>{}</td><td>{:.2}</td><td>{}</td><td>{}</td></tr>\n", xml_escape(network.id_to_name(station_id)),
+                boardings, average_wait, left_behind, self.station_transfers[station_id]);
+            station_names.push(network.id_to_name(station_id).clone());
+            average_waits.push(average_wait);
+        }
+        html += "</table>\n";
+        html += &svg_bar_chart(&station_names, &[("Average wait (minutes)", "#4a7ebb", average_waits)]);
+
+        // Customers carried between each pair of neighbouring stations over the day, in both directions
+        for line in lines.iter() {
+            let mut segments = Vec::new();
+            let (mut eastward, mut westward) = (Vec::new(), Vec::new());
+            for station_id in 0..line.length() - 1 {
+                let (a, b) = (line.network_ids[station_id], line.network_ids[station_id + 1]);
+                segments.push(format!("{} - {}", network.id_to_name(a), network.id_to_name(b)));
+                eastward.push(self.segment_load[a * self.od_stations + b]);
+                westward.push(self.segment_load[b * self.od_stations + a]);
+            }
+            html += &format!("<h2>Load Profile: {}</h2>\n", xml_escape(&line.name));
+            html += &svg_bar_chart(&segments, &[("Eastward customers", "#c0504d", eastward), ("Westward customers", "#4a7ebb", westward)]);
+        }
+
+        let mut bins = vec![0.0; 10];
+        for percent in self.train_util_percent.iter() {
+            bins[((percent / 10.0) as usize).min(9)] += 1.0;
+        }
+        let bin_names: Vec<String> = (0..10).map(|bin| format!("{}-{}%", bin * 10, (bin + 1) * 10)).collect();
+        html += "<h2>Train Utilization</h2>\n";
+        html += &svg_bar_chart(&bin_names, &[("Trains", "#9bbb59", bins)]);
+
+        html += "</body>\n</html>\n";
+        return std::fs::write(path, html).map_err(|e| format!("Could not write {}: {}", path, e));
+    }
+}
+
+#[derive(Clone)]
+struct CostModel { // What an hour of service, a car-km, a kWh and a customer's time are worth, all in dollars
+    train_hour: f32,
+    standby_hour: f32,
+    car_km: f32,
+    kwh: f32,
+    wait_minute: f32,
+    ride_minute: f32,
+    lost_trip: f32,
+}
+
+impl CostModel {
+
+    fn default_costs() -> CostModel {
+        return CostModel {train_hour: COST_PER_TRAIN_HOUR, standby_hour: COST_PER_STANDBY_HOUR, car_km: COST_PER_CAR_KM, kwh: COST_PER_KWH,
+            wait_minute: VALUE_OF_WAITING_MINUTE, ride_minute: VALUE_OF_RIDING_MINUTE, lost_trip: COST_PER_LOST_TRIP};
+    }
+
+    fn from_file(path : &str) -> Result<CostModel, String> {
+        // Reads the costs to use, one per line: <item>,<dollars>
+        // Items are train_hour, standby_hour, car_km, kwh, wait_minute, ride_minute and lost_trip, any not given keep their default
+        // Blank lines and lines starting with # are ignored
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read cost file {}: {}", path, e))?;
+        let mut costs = CostModel::default_costs();
+
+        for (line_number, text) in contents.lines().enumerate() {
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') { continue; }
+
+            let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+            if fields.len() != 2 { return Err(format!("Cost file line {} is invalid", line_number + 1)); }
+            let value : f32 = fields[1].parse().map_err(|_| format!("Cost file line {} has an invalid amount", line_number + 1))?;
+            match fields[0].to_lowercase().as_str() {
+                "train_hour" => costs.train_hour = value,
+                "standby_hour" => costs.standby_hour = value,
+                "car_km" => costs.car_km = value,
+                "kwh" => costs.kwh = value,
+                "wait_minute" => costs.wait_minute = value,
+                "ride_minute" => costs.ride_minute = value,
+                "lost_trip" => costs.lost_trip = value,
+                _ => return Err(format!("Cost file line {} has an unknown item: {}", line_number + 1, fields[0])),
+            }
+        }
+        return Ok(costs);
+    }
+}
+
+#[derive(Clone)]
+struct Station {
     name: String,
     customers: Vec<Customer>,
     west_customers: VecDeque<Customer>,
     east_customers: VecDeque<Customer>,
     customer_iat: f32,
+    entrances: Vec<f32>, // Where the escalators and stairs reach the platform, 0 is the west end and 1 the east end
+    platform_capacity: usize, // Customers each platform can safely hold
+    concourse: VecDeque<Customer>, // Customers held at the faregates until there is room on their platform
 }
 
 impl Station {
 
     fn new(new_name: String, iat: f32) -> Station {
         let new_vec = Vec::new();
-        return Station {name: new_name, customers: new_vec, east_customers : VecDeque::new(), west_customers : VecDeque::new(), customer_iat: iat};
+        return Station {name: new_name, customers: new_vec, east_customers : VecDeque::new(), west_customers : VecDeque::new(), customer_iat: iat,
+            entrances: vec![0.5], platform_capacity: usize::MAX, concourse: VecDeque::new()};
     }
 
     fn add_customer(&mut self, new_cust: Customer) {
@@ -223,6 +1840,27 @@ impl Station {
         }
     }
 
+    fn enter_through_gates(&mut self, new_cust: Customer) -> bool {
+        // Lets a customer coming in from the street onto their platform, or holds them in the concourse if it is full
+        // Returns true if they were held
+        let direction = new_cust.get_direction();
+        let queued_ahead = self.concourse.iter().any(|c| c.get_direction() == direction);
+        if self.queue_length(direction) >= self.platform_capacity || queued_ahead {
+            self.concourse.push_back(new_cust);
+            return true;
+        }
+        self.add_customer(new_cust);
+        return false;
+    }
+
+    fn queue_length(&self, direction : i8) -> usize {
+        if direction == EASTWARD {
+            return self.east_customers.len();
+        } else {
+            return self.west_customers.len();
+        }
+    }
+
     fn get_true_iat(&self, time : f32) -> f32 {
         // Returns an actual iat given our current minute
 
@@ -243,27 +1881,131 @@ impl Station {
    }
 }
 
-struct Line { // NOTE: For the sake of this simulator, we assume the line has no braches
+#[derive(Clone)]
+struct Line { // NOTE: A line has no braches of its own, branching services are separate lines sharing stations
     name: String,
     stations: Vec<Station>,
+    network_ids: Vec<usize>, // Per station, the id of the physical station in the network (filled in by Network::link)
     inter_station_traveltimes: Vec<f32>,
+    inter_station_distances: Vec<f32>, // km
+    inter_station_gradients: Vec<f32>, // Average percent grade heading east (negative if the track drops)
     east_trains: VecDeque<usize>, // Used to store trains ready to start their journey east
     west_trains: VecDeque<usize>, // Used to store trains ready to start their journey west
     trains_deployed: f32,
+    east_blocked_until: Vec<f32>, // Per station, time until eastward trains can head to it (used when a train fails)
+    west_blocked_until: Vec<f32>,
+    closures: Vec<(usize, f32, f32)>, // STATION ID, FROM MINUTE, TO MINUTE
+    single_track: Vec<SingleTrackSection>,
+    turnbacks: Vec<bool>, // Per station, can trains reverse here
+    service_patterns: Vec<ServicePattern>,
+    major_stations: Vec<bool>, // Stations every stopping pattern serves
+    stopping_patterns: Vec<StoppingPattern>,
+    stopping_releases: [u32; 2], // Trains released so far going EAST, WEST (used to alternate stopping patterns)
+    timetable: [Vec<f32>; 2], // Scheduled departure minutes from the terminals going EAST, WEST (only used by timetable dispatch)
+    positions: Vec<(f32, f32)>, // Per station, latitude and longitude (laid out due east of SCHEMATIC_ORIGIN unless read from a GTFS feed)
+}
+
+#[derive(Clone)]
+struct SingleTrackSection { // Stretch of the line where both directions share one track for a while
+    first_station: usize,
+    last_station: usize,
+    from: f32,
+    to: f32,
+    direction: i8, // Direction of the trains currently using the section
+    trains_inside: Vec<usize>,
+    waiting: VecDeque<(usize, usize)>, // TRAIN ID, NEXT STATION ID of trains held outside the section
+}
+
+impl SingleTrackSection {
+
+    fn entered_by(&self, direction : i8, station_id : usize, time : f32) -> bool {
+        // Would a train leaving the given station in the given direction be entering this section
+        if time < self.from || time >= self.to { return false; }
+        return (direction == EASTWARD && station_id == self.first_station) || (direction == WESTWARD && station_id == self.last_station);
+    }
+
+    fn exited_by(&self, direction : i8, station_id : usize) -> bool {
+        return (direction == EASTWARD && station_id == self.last_station) || (direction == WESTWARD && station_id == self.first_station);
+    }
+
+    fn can_enter(&self, direction : i8) -> bool {
+        // Trains can follow each other through the section, but not if the other direction has been waiting (so directions alternate)
+        if self.trains_inside.is_empty() { return true; }
+        return self.direction == direction && self.waiting.is_empty();
+    }
 }
 
 impl Line {
 
-    fn new(line_name: String, station_names: &[&str], station_traveltimes: Vec<f32>, iats : Vec<f32>) -> Line {
+    fn new(line_name: String, station_names: &[&str], station_traveltimes: Vec<f32>, station_distances: Vec<f32>, iats : Vec<f32>) -> Line {
         let mut station_vec = Vec::new();
         for i in 0..station_names.len() {
             station_vec.push(Station::new(String::from(station_names[i]), iats[i]));
+            station_vec[i].entrances = vec![DEFAULT_ENTRANCES[i % DEFAULT_ENTRANCES.len()]]; // NOTE: Placeholder layout until real station plans are added
         }
         
+        // Stations are spaced out by their distances along a straight line, only used where we have to give coordinates
+        let mut positions = vec![SCHEMATIC_ORIGIN];
+        for distance in station_distances.iter() {
+            let (lat, lon) = positions[positions.len() - 1];
+            positions.push((lat, lon + (distance / (EARTH_RADIUS * lat.to_radians().cos())).to_degrees()));
+        }
+
         let east_trains: VecDeque<usize> = VecDeque::new();
         let west_trains: VecDeque<usize> = VecDeque::new();
         return Line {stations: station_vec, name: line_name, east_trains: east_trains, west_trains: west_trains, 
-            inter_station_traveltimes : station_traveltimes, trains_deployed : 0.0};
+            inter_station_traveltimes : station_traveltimes, inter_station_distances : station_distances,
+            inter_station_gradients : vec![0.0; station_names.len() - 1], trains_deployed : 0.0,
+            east_blocked_until : vec![0.0; station_names.len()], west_blocked_until : vec![0.0; station_names.len()],
+            closures : Vec::new(), single_track : Vec::new(), turnbacks : vec![false; station_names.len()], service_patterns : Vec::new(),
+            major_stations : vec![true; station_names.len()], stopping_patterns : Vec::new(), stopping_releases : [0, 0],
+            timetable : [Vec::new(), Vec::new()], positions : positions,
+            network_ids : Vec::new()};
+    }
+
+    fn set_major_stations(&mut self, major_names : &[&str]) {
+        // Only the named stations are served by every stopping pattern
+        for station_id in 0..self.length() {
+            self.major_stations[station_id] = major_names.iter().any(|name| self.stations[station_id].name.eq_ignore_ascii_case(name));
+        }
+    }
+
+    fn average_arrival_rate(&self) -> f32 {
+        // Customers per minute arriving at the line's stations, averaged over the day
+        let mut arrival_rate = 0.0;
+        for station in &self.stations {
+            let mut time = 0.5;
+            while time < SIMULATION_LENGTH {
+                arrival_rate += station.get_true_iat(time) / SIMULATION_LENGTH;
+                time += 1.0;
+            }
+        }
+        return arrival_rate;
+    }
+
+    fn expected_population_headway(&self, multi : f32, average_capacity : f32) -> f32 {
+        // Roughly how often the population-based dispatch sends trains out on this line over the day
+        // Customers pile up at the line's average arrival rate for half a headway, which sets how soon the next train goes
+        let arrival_rate = self.average_arrival_rate();
+        let mut headway = (10.0 + 2.5) / 2.0;
+        for _ in 0..30 {
+            let trainfuls_normalized = clamp((arrival_rate * headway / 2.0) / average_capacity * multi / 6.0, 0.0, 1.0);
+            headway = (headway + ((1.0 - trainfuls_normalized) * 10.0) + (trainfuls_normalized * 2.5)) / 2.0;
+        }
+        return headway;
+    }
+
+    fn waiting_customers(&self) -> f32 {
+        // Customers waiting on any of this line's platforms or held in their concourses
+        let mut waiting = 0;
+        for station in &self.stations {
+            waiting += station.east_customers.len() + station.west_customers.len() + station.concourse.len();
+        }
+        return waiting as f32;
+    }
+
+    fn total_traveltime(&self) -> f32 {
+        return self.inter_station_traveltimes.iter().sum();
     }
 
     fn add_cust_at(&mut self, new_cust: Customer, station_index: usize) {
@@ -277,134 +2019,725 @@ impl Line {
     fn id_to_name(&self, station_id: usize) -> &String {
         return &self.stations[station_id].name;
     }
+
+    fn name_to_id(&self, station_name: &str) -> Option<usize> {
+        return self.stations.iter().position(|station| station.name.eq_ignore_ascii_case(station_name));
+    }
+
+    fn set_stopping_patterns(&mut self, policy : &StoppingPolicy) {
+        // Builds the stopping patterns trains can be given, pattern 0 is always used when a policy only has one
+        let all_stops = StoppingPattern {name : String::from("All stops"), stops : vec![true; self.length()]};
+        match policy {
+            StoppingPolicy::AllStops() => self.stopping_patterns = vec![all_stops],
+            StoppingPolicy::SkipStop() => {
+                // Minor stations alternate between A and B down the line
+                let mut a_stops = self.major_stations.clone();
+                let mut b_stops = self.major_stations.clone();
+                let mut next_is_a = true;
+                for station_id in 0..self.length() {
+                    if self.major_stations[station_id] { continue; }
+                    if next_is_a {a_stops[station_id] = true;} else {b_stops[station_id] = true;}
+                    next_is_a = !next_is_a;
+                }
+                self.stopping_patterns = vec![StoppingPattern {name : String::from("A"), stops : a_stops},
+                    StoppingPattern {name : String::from("B"), stops : b_stops}];
+            },
+            StoppingPolicy::Express(_) => {
+                self.stopping_patterns = vec![all_stops, StoppingPattern {name : String::from("Express"), stops : self.major_stations.clone()}];
+            },
+        }
+    }
+
+    fn plan_transfer(&self, origin : usize, destination : usize) -> Option<usize> {
+        // Returns where a customer has to change trains if no stopping pattern serves both of their stations
+        let connects = |a : usize, b : usize| self.stopping_patterns.iter().any(|pattern| pattern.stops[a] && pattern.stops[b]);
+        if connects(origin, destination) { return None; }
+
+        // Pick the transfer station that adds the least extra riding
+        let mut best : Option<usize> = None;
+        let mut best_distance = usize::MAX;
+        for station_id in 0..self.length() {
+            if station_id == origin || station_id == destination || !connects(origin, station_id) || !connects(station_id, destination) {
+                continue;
+            }
+            let distance = origin.abs_diff(station_id) + station_id.abs_diff(destination);
+            if distance < best_distance {
+                best = Some(station_id);
+                best_distance = distance;
+            }
+        }
+        return best;
+    }
+
+    fn is_closed(&self, station_id: usize, time: f32) -> bool {
+        for (closed_id, from, to) in &self.closures {
+            if *closed_id == station_id && time >= *from && time < *to {
+                return true;
+            }
+        }
+        return false;
+    }
     
-    fn release_westward(&mut self) -> Option<usize> {
-        // Take the next train index out of the westward train queue and return it
-        self.trains_deployed += 1.0;
-        return self.west_trains.pop_front();
+    fn release_westward(&mut self, position : usize) -> Option<usize> {
+        // Take the train index at the given position out of the westward train queue and return it
+        let train = self.west_trains.remove(position);
+        if train.is_some() { self.trains_deployed += 1.0; }
+        return train;
     }
 
-    fn release_eastward(&mut self) -> Option<usize> {
-        // Take the next train index out of the eastward train queue and return it
-        self.trains_deployed += 1.0;
-        return self.east_trains.pop_front();
+    fn release_eastward(&mut self, position : usize) -> Option<usize> {
+        // Take the train index at the given position out of the eastward train queue and return it
+        let train = self.east_trains.remove(position);
+        if train.is_some() { self.trains_deployed += 1.0; }
+        return train;
     }
 
 }
 
-#[derive(Debug)]
-struct Train {
-    id: u8,
-    capacity: f32,
-    active: bool, // Wether or not this train is in our system or on standby
-    at_station: usize, // Current station we are at (or are headed to)
-    in_motion: bool, // If this train is between stations or not
-    direction: i8,
-    customer_list: Vec<Customer>,
-    riding_customers: f32,
-    percent_full_total: f32,
-    percent_full_max: f32,
-    percent_full_test_amount: f32,
-    times_full : f32,
+struct Network { // The physical stations served by the lines, a station on more than one line is where customers change lines
+    station_names: Vec<String>,
+    home_stations: Vec<(usize, usize)>, // Per network station, the LINE ID, STATION ID whose arrival rate it uses
+    routes: Vec<Vec<Route>>, // Per origin-destination pair [origin * stations + destination], the routes customers pick from (cheapest first)
+    graph: Option<RouteGraph>, // Kept from planning the routes, to find a way around closed stations
+    route_choice: RouteChoice,
+    interlined_free_at: HashMap<(usize, usize), f32>, // Per FROM, TO network station of track used by more than one line, when it is free again
+    line_count: usize,
 }
 
-impl Train {
+impl Network {
+
+    fn link(lines : &mut [Line], route_choice : RouteChoice) -> Network {
+        // Matches up stations with the same name on different lines and finds the track they share
+        let mut network = Network {station_names : Vec::new(), home_stations : Vec::new(), routes : Vec::new(), graph : None, route_choice : route_choice,
+            interlined_free_at : HashMap::new(), line_count : lines.len()};
+        for line_id in 0..lines.len() {
+            lines[line_id].network_ids.clear();
+            for station_id in 0..lines[line_id].length() {
+                let name = lines[line_id].id_to_name(station_id).clone();
+                let network_id = match network.name_to_id(&name) {
+                    Some(network_id) => network_id,
+                    None => {
+                        network.station_names.push(name);
+                        network.home_stations.push((line_id, station_id));
+                        network.station_names.len() - 1
+                    },
+                };
+                lines[line_id].network_ids.push(network_id);
+            }
+        }
 
-    fn new(new_id : u8) -> Train {
-        return Train{id : new_id, capacity : TRAIN_CAPACITY, 
-            active : false, at_station : 0, in_motion : false, direction : EASTWARD, customer_list: Vec::new(),
-            percent_full_total: 0.0, percent_full_test_amount: 0.0, riding_customers: 0.0, percent_full_max : 0.0, times_full: 0.0};
+        let mut segment_lines: HashMap<(usize, usize), u32> = HashMap::new();
+        for line in lines.iter() {
+            for station_id in 1..line.length() {
+                let (a, b) = (line.network_ids[station_id - 1], line.network_ids[station_id]);
+                *segment_lines.entry((a, b)).or_insert(0) += 1;
+                *segment_lines.entry((b, a)).or_insert(0) += 1;
+            }
+        }
+        for (segment, line_count) in segment_lines {
+            if line_count > 1 { network.interlined_free_at.insert(segment, 0.0); }
+        }
+        return network;
     }
 
-    fn arrive_at(&mut self, station_id : usize) {
-        self.in_motion = false;
-        self.at_station = station_id;
+    fn plan_trips(&mut self, lines : &[Line], expected_waits : &[f32], crowding_factors : &[f32]) {
+        // Finds the routes customers can take between every pair of stations
+        // Only the cheapest one is kept unless customers pick their route at random
+        let graph = RouteGraph::new(lines, expected_waits, crowding_factors);
+        let route_count = if matches!(self.route_choice, RouteChoice::Shortest()) {1} else {ROUTE_ALTERNATIVES};
+        let stations = self.length();
+        self.routes = vec![Vec::new(); stations * stations];
+
+        for origin in 0..stations {
+            for destination in 0..stations {
+                // Each route found makes the trains it rides less attractive, so the next search tries something else
+                let mut penalties: HashMap<(usize, usize), f32> = HashMap::new();
+                let mut routes: Vec<Route> = Vec::new();
+                for _ in 0..route_count {
+                    let path = match graph.cheapest_path(origin, destination, &penalties) {
+                        Some(path) => path,
+                        None => break, // Not connected
+                    };
+                    let route = graph.path_to_route(&path, lines);
+                    for step in 1..path.len() {
+                        let edge_cost = graph.edge_cost(path[step - 1], path[step]);
+                        *penalties.entry((path[step - 1], path[step])).or_insert(0.0) += edge_cost * ROUTE_OVERLAP_PENALTY;
+                    }
+                    if !routes.iter().any(|other| other.legs == route.legs) { routes.push(route); }
+                }
+                routes.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+                self.routes[origin * stations + destination] = routes;
+            }
+        }
+        self.graph = Some(graph);
     }
 
-    fn leave_to(&mut self, station_id : usize) {
-        self.in_motion = true;
-        self.at_station = station_id;
+    fn route_avoiding(&self, lines : &[Line], origin : usize, destination : usize, time : f32) -> Option<Route> {
+        // Cheapest route that doesn't change trains at, start from or end at a station closed at the given time
+        // Trains still run through closed stations, so riding past one is fine
+        let graph = self.graph.as_ref()?;
+        let closed = |node : usize| lines[graph.nodes[node].0].is_closed(graph.nodes[node].1, time);
+        let mut penalties: HashMap<(usize, usize), f32> = HashMap::new();
+        for node in 0..graph.nodes.len() {
+            for (other, _) in &graph.edges[node] {
+                if graph.nodes[*other].0 != graph.nodes[node].0 && (closed(node) || closed(*other)) {
+                    penalties.insert((node, *other), f32::INFINITY);
+                }
+            }
+        }
+        let route = graph.path_to_route(&graph.cheapest_path(origin, destination, &penalties)?, lines);
+        if route.legs.iter().any(|leg| lines[leg.line].is_closed(leg.from, time) || lines[leg.line].is_closed(leg.to, time)) {
+            return None;
+        }
+        return Some(route);
     }
 
-    fn switch_direction(&mut self) {
-        // Switches the direction of the given train
-        if self.direction == EASTWARD {
-            self.direction = WESTWARD;
-        } else {
-            self.direction = EASTWARD;
+    fn plan_trip(&self, origin : usize, destination : usize, rng : &mut ChaCha8Rng) -> (Vec<Leg>, f32, bool) {
+        // Picks the route a new customer will take, returns its legs, its generalized cost and whether it was the cheapest route
+        let routes = &self.routes[origin * self.length() + destination];
+        if routes.is_empty() { return (Vec::new(), 0.0, true); }
+        let mut choice = 0;
+        if let RouteChoice::Logit(scale) = self.route_choice {
+            if routes.len() > 1 {
+                let weights: Vec<f32> = routes.iter().map(|route| (-scale * (route.cost - routes[0].cost)).exp()).collect();
+                let mut pick = rand::Rng::gen::<f32>(rng) * weights.iter().sum::<f32>();
+                while choice + 1 < weights.len() && pick >= weights[choice] {
+                    pick -= weights[choice];
+                    choice += 1;
+                }
+            }
         }
+        return (routes[choice].legs.clone(), routes[choice].cost, choice == 0);
+    }
 
+    fn length(&self) -> usize {
+        return self.station_names.len();
     }
 
-    fn disable(&mut self) {
-        // Used when we have reached the end of the line and have been adeed into a queue to be re-deployed
-        self.in_motion = false;
-        self.active = false;
+    fn is_multi_line(&self) -> bool {
+        return self.line_count > 1;
     }
 
-    fn has_capacity(&self) -> bool {
-        // Returns true if we have room left for passengers, false if we don't
-        return self.capacity > self.riding_customers;
+    fn id_to_name(&self, station_id : usize) -> &String {
+        return &self.station_names[station_id];
     }
 
-    fn poll_usage(&mut self) -> bool{
-        // Returns true if we have room left for passengers, false if we don't
-        let cur_cap_percent = self.riding_customers / self.capacity;
-        self.percent_full_total += cur_cap_percent;
-        self.percent_full_test_amount += 1.0;
-        if cur_cap_percent > self.percent_full_max {
-            self.percent_full_max = cur_cap_percent;
-        }
-        if cur_cap_percent > 0.99 {
-            self.times_full += 1.0;
-            return true;
-        } else {
-            return false;
-        }
+    fn name_to_id(&self, station_name : &str) -> Option<usize> {
+        return self.station_names.iter().position(|name| name.eq_ignore_ascii_case(station_name));
     }
+}
 
+#[derive(Clone)]
+enum RouteChoice {
+    Shortest(), // Every customer takes the route with the lowest generalized cost
+    Logit(f32), // SCALE, customers pick between routes at random, weighted by exp(-scale * generalized cost)
 }
 
-#[derive(Debug)]
-struct Customer {
-    sat: f32, // Time at which we arrive at the station
-    tbt: f32, // Time at which we board the train
-    tet: f32, // Time at which we left the train
+#[derive(Clone)]
+struct Route { // One way of getting between two stations
+    legs: Vec<Leg>,
+    cost: f32, // Generalized minutes: riding, expected waits, walking and transfer penalties
+}
 
-    start_at: usize, // The station this customert arrived at
-    end_at: usize, // The station this customer wants to reach
-    assist: bool, // Does this customer need priority seating?
+struct RouteGraph { // Every platform in the network and the ways of getting from one to another, used to plan routes
+    nodes: Vec<(usize, usize)>, // LINE ID, STATION ID
+    network_ids: Vec<usize>, // Per node, the network station it is at
+    edges: Vec<Vec<(usize, f32)>>, // Per node, the NODE reachable from it and the generalized cost of getting there
+    expected_waits: Vec<f32>, // Per line, how long customers expect to wait for one of its trains
 }
 
-impl Customer {
+impl RouteGraph {
+
+    fn new(lines : &[Line], expected_waits : &[f32], crowding_factors : &[f32]) -> RouteGraph {
+        // Riding costs the travel time plus the stop weighted by how crowded the line is, changing lines costs the walk,
+        // the transfer penalty and another wait
+        let mut nodes: Vec<(usize, usize)> = Vec::new();
+        let mut network_ids = Vec::new();
+        for line_id in 0..lines.len() {
+            for station_id in 0..lines[line_id].length() {
+                nodes.push((line_id, station_id));
+                network_ids.push(lines[line_id].network_ids[station_id]);
+            }
+        }
 
-    fn empty() -> Customer { // Used for testing
-        return Customer {sat: 0.0, tbt: 0.0, tet: 0.0, start_at: 0, end_at: 0, assist: false};
+        let mut edges: Vec<Vec<(usize, f32)>> = vec![Vec::new(); nodes.len()];
+        for node in 0..nodes.len() {
+            let (line_id, station_id) = nodes[node];
+            if station_id + 1 < lines[line_id].length() {
+                let ride_time = (lines[line_id].inter_station_traveltimes[station_id] + STATION_DWELL_TIME) * crowding_factors[line_id];
+                edges[node].push((node + 1, ride_time));
+                edges[node + 1].push((node, ride_time));
+            }
+            for other in 0..nodes.len() {
+                if nodes[other].0 != line_id && network_ids[other] == network_ids[node] {
+                    edges[node].push((other, TRANSFER_WALK_TIME + TRANSFER_PENALTY + expected_waits[nodes[other].0]));
+                }
+            }
+        }
+        return RouteGraph {nodes : nodes, network_ids : network_ids, edges : edges, expected_waits : expected_waits.to_vec()};
     }
 
-    fn get_direction(&self) -> i8 {
-        // Returns the direction this customer wants to go in (EASTWARD || WESTWARD)
-        if self.start_at < self.end_at {
-            return WESTWARD;
-        } else {
-            return EASTWARD;
-        }
+    fn edge_cost(&self, from : usize, to : usize) -> f32 {
+        return self.edges[from].iter().find(|(other, _)| *other == to).expect("ERR: NO SUCH EDGE").1;
     }
 
-}
+    fn cheapest_path(&self, origin : usize, destination : usize, penalties : &HashMap<(usize, usize), f32>) -> Option<Vec<usize>> {
+        // Dijkstra from every platform of the origin station at once to the cheapest platform of the destination
+        let mut cost = vec![f32::INFINITY; self.nodes.len()];
+        let mut previous: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut done = vec![false; self.nodes.len()];
+        for node in 0..self.nodes.len() {
+            if self.network_ids[node] == origin { cost[node] = 0.0; }
+        }
+        loop {
+            let mut next = None;
+            for node in 0..self.nodes.len() {
+                if !done[node] && cost[node].is_finite() && next.is_none_or(|best : usize| cost[node] < cost[best]) {
+                    next = Some(node);
+                }
+            }
+            let node = match next {
+                Some(node) => node,
+                None => break,
+            };
+            done[node] = true;
+            for (other, edge_cost) in &self.edges[node] {
+                let penalty = penalties.get(&(node, *other)).copied().unwrap_or(0.0);
+                if cost[node] + edge_cost + penalty < cost[*other] {
+                    cost[*other] = cost[node] + edge_cost + penalty;
+                    previous[*other] = Some(node);
+                }
+            }
+        }
 
+        let mut end = None;
+        for node in 0..self.nodes.len() {
+            if self.network_ids[node] == destination && end.is_none_or(|best : usize| cost[node] < cost[best]) {
+                end = Some(node);
+            }
+        }
+        let end = match end {
+            Some(end) if cost[end].is_finite() => end,
+            _ => return None,
+        };
 
-//// Event Code //// 
-fn dummy_event( sim : Simulation) -> Simulation {
-    // Does nothing, used as a backup/to end the simulation
-    return sim;
-}
+        let mut path = vec![end];
+        while let Some(node) = previous[*path.last().unwrap()] {
+            path.push(node);
+        }
+        path.reverse();
+        return Some(path);
+    }
 
-fn poll_stats( mut sim : Simulation) -> Simulation {
-    // Updates the bookkeeping of the sim with some handy info such as the number of customers waiting
+    fn path_to_route(&self, path : &[usize], lines : &[Line]) -> Route {
+        // One leg per line ridden, each split wherever the line's stopping patterns force a change of train
+        let mut cost = self.expected_waits[self.nodes[path[0]].0]; // Waiting for the first train
+        for step in 1..path.len() {
+            cost += self.edge_cost(path[step - 1], path[step]);
+        }
+
+        let mut legs = Vec::new();
+        let mut leg_start = path[0];
+        for step in 1..=path.len() {
+            if step < path.len() && self.nodes[path[step]].0 == self.nodes[path[step - 1]].0 { continue; }
+            let (line_id, from) = self.nodes[leg_start];
+            let to = self.nodes[path[step - 1]].1;
+            match lines[line_id].plan_transfer(from, to) {
+                Some(transfer_station) if from != to => {
+                    legs.push(Leg {line : line_id, from : from, to : transfer_station});
+                    legs.push(Leg {line : line_id, from : transfer_station, to : to});
+                    cost += TRANSFER_PENALTY + self.expected_waits[line_id];
+                },
+                _ => legs.push(Leg {line : line_id, from : from, to : to}),
+            }
+            if step < path.len() { leg_start = path[step]; }
+        }
+        return Route {legs : legs, cost : cost};
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TrainType { // A kind of consist in the fleet (model + number of cars)
+    name: String,
+    cars: u8,
+    capacity: f32,
+    seats: f32,
+    standing_area: f32, // m^2 of floor space for standees
+    acceleration: f32, // m/s^2
+    mtbf: f32, // Mean minutes in service between failures
+    mean_repair_time: f32,
+    empty_mass: f32, // Tonnes
+}
+
+impl TrainType {
+
+    fn default_type() -> TrainType {
+        // The train every simulation used before fleets could be defined
+        let (seats, standing_area) = TrainType::default_seating(TRAIN_CARS, TRAIN_CAPACITY);
+        return TrainType {name: String::from("Mark I (4 car)"), cars: TRAIN_CARS, capacity: TRAIN_CAPACITY, seats: seats, standing_area: standing_area,
+            acceleration: TRAIN_ACCELERATION, mtbf: TRAIN_MTBF, mean_repair_time: TRAIN_MEAN_REPAIR_TIME,
+            empty_mass: CAR_MASS * TRAIN_CARS as f32};
+    }
+
+    fn default_seating(cars : u8, capacity : f32) -> (f32, f32) {
+        // Seats and standing area for a train type that doesn't give them, everyone past the seats stands at crush load when full
+        let seats = (SEATS_PER_CAR * cars as f32).min(capacity);
+        return (seats, (capacity - seats) / CRUSH_STANDING_DENSITY);
+    }
+
+    fn travel_time_adjustment(&self) -> f32 {
+        // Extra minutes (or minutes saved) per inter station run compared to the train the travel times were measured with
+        // Getting up to line speed and braking back down costs v/a seconds over cruising the whole way
+        let seconds = (LINE_SPEED / self.acceleration) - (LINE_SPEED / TRAIN_ACCELERATION);
+        return seconds / 60.0;
+    }
+}
+
+#[derive(Clone)]
+struct Fleet { // Every train type we own and how many of each
+    types: Vec<TrainType>,
+    counts: Vec<usize>,
+}
+
+impl Fleet {
+
+    fn default_fleet(trains : usize) -> Fleet {
+        return Fleet {types: vec![TrainType::default_type()], counts: vec![trains]};
+    }
+
+    fn from_file(path : &str) -> Result<Fleet, String> {
+        // Reads a fleet definition, one train type per line:
+        // <name>,<cars>,<count>,<capacity>,<assist capacity>,<acceleration>[,<mtbf>,<mean repair time>[,<seats>,<standing area m^2>[,<empty mass t>]]]
+        // Blank lines and lines starting with # are ignored
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read fleet file {}: {}", path, e))?;
+        let mut fleet = Fleet {types: Vec::new(), counts: Vec::new()};
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if fields.len() != 6 && fields.len() != 8 && fields.len() != 10 && fields.len() != 11 {
+                return Err(format!("Fleet file line {} should have 6, 8, 10 or 11 fields, found {}", line_number + 1, fields.len()));
+            }
+            let bad_field = |name : &str| format!("Fleet file line {} has an invalid {}", line_number + 1, name);
+
+            let cars : u8 = fields[1].parse().map_err(|_| bad_field("car count"))?;
+            let capacity : f32 = fields[3].parse().map_err(|_| bad_field("capacity"))?;
+            let acceleration : f32 = fields[5].parse().map_err(|_| bad_field("acceleration"))?;
+            if cars == 0 { return Err(bad_field("car count (must be at least 1)")); }
+            if !capacity.is_finite() || capacity <= 0.0 { return Err(bad_field("capacity (must be more than 0)")); }
+            if !acceleration.is_finite() || acceleration <= 0.0 { return Err(bad_field("acceleration (must be more than 0)")); }
+            let (mut mtbf, mut mean_repair_time) = (TRAIN_MTBF, TRAIN_MEAN_REPAIR_TIME);
+            if fields.len() >= 8 {
+                mtbf = fields[6].parse().map_err(|_| bad_field("MTBF"))?;
+                mean_repair_time = fields[7].parse().map_err(|_| bad_field("repair time"))?;
+                if !mtbf.is_finite() || mtbf <= 0.0 { return Err(bad_field("MTBF (must be more than 0)")); }
+                if !mean_repair_time.is_finite() || mean_repair_time <= 0.0 { return Err(bad_field("repair time (must be more than 0)")); }
+            }
+            // NOTE: Assist spaces aren't simulated yet, the column is only checked so fleet files keep their layout
+            let _assist_capacity : u16 = fields[4].parse().map_err(|_| bad_field("assist capacity"))?;
+            let (mut seats, mut standing_area) = TrainType::default_seating(cars, capacity);
+            if fields.len() >= 10 {
+                seats = fields[8].parse().map_err(|_| bad_field("seat count"))?;
+                standing_area = fields[9].parse().map_err(|_| bad_field("standing area"))?;
+                if !seats.is_finite() || seats < 0.0 { return Err(bad_field("seat count (must be 0 or more)")); }
+                if !standing_area.is_finite() || standing_area < 0.0 { return Err(bad_field("standing area (must be 0 or more)")); }
+            }
+            let mut empty_mass = CAR_MASS * cars as f32;
+            if fields.len() == 11 {
+                empty_mass = fields[10].parse().map_err(|_| bad_field("empty mass"))?;
+                if !empty_mass.is_finite() || empty_mass <= 0.0 { return Err(bad_field("empty mass (must be more than 0)")); }
+            }
+
+            fleet.types.push(TrainType {
+                name: String::from(fields[0]),
+                cars: cars,
+                capacity: capacity,
+                seats: seats,
+                standing_area: standing_area,
+                acceleration: acceleration,
+                mtbf: mtbf,
+                mean_repair_time: mean_repair_time,
+                empty_mass: empty_mass,
+            });
+            fleet.counts.push(fields[2].parse().map_err(|_| bad_field("train count"))?);
+        }
+
+        if fleet.total_trains() == 0 {
+            return Err(format!("Fleet file {} does not define any trains", path));
+        }
+        if fleet.total_trains() > usize::from(u8::MAX) + 1 {
+            return Err(format!("Fleet file {} defines more than {} trains", path, usize::from(u8::MAX) + 1));
+        }
+        return Ok(fleet);
+    }
+
+    fn total_trains(&self) -> usize {
+        return self.counts.iter().sum();
+    }
+
+    fn average_capacity(&self) -> f32 {
+        let mut total_capacity = 0.0;
+        for i in 0..self.types.len() {
+            total_capacity += self.types[i].capacity * self.counts[i] as f32;
+        }
+        return total_capacity / self.total_trains() as f32;
+    }
+
+    fn average_seating(&self) -> (f32, f32) {
+        // Seats and standing area of the average train in the fleet
+        let (mut seats, mut standing_area) = (0.0, 0.0);
+        for i in 0..self.types.len() {
+            seats += self.types[i].seats * self.counts[i] as f32;
+            standing_area += self.types[i].standing_area * self.counts[i] as f32;
+        }
+        return (seats / self.total_trains() as f32, standing_area / self.total_trains() as f32);
+    }
+
+    fn build_trains(&self) -> Vec<Train> {
+        // Creates every train in the fleet, types are interleaved so each terminal gets a mix of consists
+        let mut train_list: Vec<Train> = Vec::new();
+        let mut remaining = self.counts.clone();
+        while remaining.iter().any(|&count| count > 0) {
+            for type_id in 0..self.types.len() {
+                if remaining[type_id] > 0 {
+                    train_list.push(Train::new(train_list.len() as u8, type_id, &self.types[type_id]));
+                    remaining[type_id] -= 1;
+                }
+            }
+        }
+        return train_list;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Movement { // One train's visit to one station
+    train: usize,
+    line: usize,
+    trip: u32,
+    direction: i8,
+    station: usize, // Station ID on the train's line
+    arrival: f32,
+    departure: f32,
+    stopped: bool, // False if the train passed through without stopping
+    riders: f32, // Customers on board when it left
+    capacity: f32,
+}
+
+#[derive(Debug)]
+struct Train {
+    id: u8,
+    type_id: usize, // Index of this train's type in the fleet
+    line: usize, // The line this train runs on
+    capacity: f32,
+    seats: f32,
+    standing_area: f32,
+    cars: u8,
+    empty_mass: f32, // Tonnes, including any coupled units
+    energy_used: f32, // kWh drawn over the day
+    movement: Option<usize>, // Index of the logged visit to the station this train is at, until it leaves
+    coupled_units: Vec<usize>, // Trains coupled behind this one, they ride along until uncoupled
+    ready_at: f32, // Time at which any coupling work on this train is done
+    trip: u32, // How many times this train has been released
+    pending_delay: f32, // Minutes this train is stuck for due to a failure, applied to its next event
+    out_of_service: bool, // Pulled off the line after a failure
+    route_first: usize, // First and last stations this train serves on its current trip
+    route_last: usize,
+    pattern: usize, // Stopping pattern for the current trip
+    passing_through: bool, // Did not stop at the station it is leaving
+    active: bool, // Wether or not this train is in our system or on standby
+    at_station: usize, // Current station we are at (or are headed to)
+    in_motion: bool, // If this train is between stations or not
+    direction: i8,
+    customer_list: Vec<Customer>,
+    riding_customers: f32,
+    car_riders: Vec<f32>, // Customers riding in each car, cars are numbered from the west end of the platform
+    percent_full_total: f32,
+    percent_full_max: f32,
+    percent_full_test_amount: f32,
+    times_full : f32,
+}
+
+impl Train {
+
+    fn new(new_id : u8, type_id : usize, train_type : &TrainType) -> Train {
+        return Train{id : new_id, type_id : type_id, line : 0, capacity : train_type.capacity,
+            seats : train_type.seats, standing_area : train_type.standing_area,
+            cars : train_type.cars, empty_mass : train_type.empty_mass, energy_used : 0.0, movement : None, coupled_units : Vec::new(), ready_at : 0.0, trip : 0, pending_delay : 0.0, out_of_service : false,
+            route_first : 0, route_last : usize::MAX, pattern : 0, passing_through : false,
+            active : false, at_station : 0, in_motion : false, direction : EASTWARD, customer_list: Vec::new(),
+            percent_full_total: 0.0, percent_full_test_amount: 0.0, riding_customers: 0.0, car_riders: vec![0.0; train_type.cars as usize], percent_full_max : 0.0, times_full: 0.0};
+    }
+
+    fn arrive_at(&mut self, station_id : usize) {
+        self.in_motion = false;
+        self.at_station = station_id;
+    }
+
+    fn leave_to(&mut self, station_id : usize) {
+        self.in_motion = true;
+        self.at_station = station_id;
+    }
+
+    fn serves(&self, station_id : usize) -> bool {
+        // Returns true if this train stops at the given station on its current trip
+        return station_id >= self.route_first && station_id <= self.route_last;
+    }
+
+    fn turns_back_at(&self, station_id : usize) -> bool {
+        // Returns true if the given station is the far end of this train's route (but not a terminal)
+        if self.direction == EASTWARD {
+            return station_id == self.route_last;
+        } else {
+            return station_id == self.route_first;
+        }
+    }
+
+    fn switch_direction(&mut self) {
+        // Switches the direction of the given train
+        if self.direction == EASTWARD {
+            self.direction = WESTWARD;
+        } else {
+            self.direction = EASTWARD;
+        }
+
+    }
+
+    fn disable(&mut self) {
+        // Used when we have reached the end of the line and have been adeed into a queue to be re-deployed
+        self.in_motion = false;
+        self.active = false;
+    }
+
+    fn pick_car(&self, platform_position : f32) -> usize {
+        // Customers board the car in front of them, or walk to the closest car that still has room
+        let car_capacity = self.capacity / self.cars as f32;
+        let nearest = ((platform_position * self.cars as f32) as usize).min(self.car_riders.len() - 1);
+        let mut best = nearest;
+        for car in 0..self.car_riders.len() {
+            if self.car_riders[car] < car_capacity && (self.car_riders[best] >= car_capacity || car.abs_diff(nearest) < best.abs_diff(nearest)) {
+                best = car;
+            }
+        }
+        return best;
+    }
+
+    fn has_capacity(&self) -> bool {
+        // Returns true if we have room left for passengers, false if we don't
+        return self.capacity > self.riding_customers;
+    }
+
+    fn poll_usage(&mut self) -> bool{
+        // Returns true if we have room left for passengers, false if we don't
+        let cur_cap_percent = self.riding_customers / self.capacity;
+        return self.record_usage(cur_cap_percent);
+    }
+
+    fn record_usage(&mut self, cur_cap_percent : f32) -> bool {
+        // Adds a usage sample to this train's stats, coupled units get the same sample as the train leading them
+        self.percent_full_total += cur_cap_percent;
+        self.percent_full_test_amount += 1.0;
+        if cur_cap_percent > self.percent_full_max {
+            self.percent_full_max = cur_cap_percent;
+        }
+        if cur_cap_percent > 0.99 {
+            self.times_full += 1.0;
+            return true;
+        } else {
+            return false;
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Leg { // Part of a customer's trip spent on one train
+    line: usize,
+    from: usize,
+    to: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Customer {
+    sat: f32, // Time at which we arrive at the station
+    tbt: f32, // Time at which we board the train
+    tet: f32, // Time at which we left the train
+
+    line: usize, // The line this customer is currently waiting on or riding
+    start_at: usize, // The station this customert arrived at
+    end_at: usize, // The station this customer wants to reach
+    assist: bool, // Does this customer need priority seating?
+
+    itinerary: VecDeque<Leg>, // The planned legs still to ride after the current one
+    transferring: bool, // Came off another line's train (or another stopping pattern) to get here
+    patience: f32, // Minutes this customer waits for a train before giving up
+    times_left_behind: u32, // Full trains that passed this customer up during their current wait
+    platform_position: f32, // Where along the platform this customer waits, 0 is the west end and 1 the east end
+    car: usize, // Car of the train this customer is riding in
+    origin: usize, // Network stations where the customer's trip started and where it ends
+    destination: usize,
+    waited: f32, // Minutes spent waiting and riding over the whole trip
+    rode: f32,
+}
+
+impl Customer {
+
+    fn empty() -> Customer { // Used for testing
+        return Customer::new(0.0, Vec::new(), 0, 0);
+    }
+
+    fn new(time : f32, mut itinerary : Vec<Leg>, origin : usize, destination : usize) -> Customer {
+        // Starts the customer off on the first leg of their planned itinerary
+        if itinerary.is_empty() { itinerary.push(Leg {line : 0, from : origin, to : destination}); }
+        let first = itinerary.remove(0);
+        return Customer {sat: time, tbt: 0.0, tet: 0.0, line: first.line, start_at: first.from, end_at: first.to, assist: false,
+            itinerary: VecDeque::from(itinerary), transferring: false, patience: f32::INFINITY, times_left_behind: 0,
+            platform_position: 0.5, car: 0, origin: origin, destination: destination, waited: 0.0, rode: 0.0};
+    }
+
+    fn get_direction(&self) -> i8 {
+        // Returns the direction this customer wants to go in (EASTWARD || WESTWARD)
+        if self.start_at < self.end_at {
+            return EASTWARD;
+        } else {
+            return WESTWARD;
+        }
+    }
+
+}
+
+
+//// Event Code //// 
+fn dummy_event( sim : Simulation) -> Simulation {
+    // Does nothing, used as a backup/to end the simulation
+    return sim;
+}
+
+fn poll_stats( mut sim : Simulation) -> Simulation {
+    // Updates the bookkeeping of the sim with some handy info such as the number of customers waiting
     // Does this once every minute
 
+    // Customers who have waited too long give up
+    if sim.mean_patience.is_some() {
+        for line_id in 0..sim.lines.len() {
+            for station_id in 0..sim.lines[line_id].length() {
+                sim.renege_customers(line_id, station_id);
+            }
+        }
+    }
+
+    // Faregates let held customers onto the platforms
+    if sim.platform_capacity.is_some() {
+        for line_id in 0..sim.lines.len() {
+            for station_id in 0..sim.lines[line_id].length() {
+                sim.meter_platforms(line_id, station_id);
+            }
+        }
+    }
+
     // Customers waiting
+    sim.bookkeeping.waiting_by_minute.push(sim.bookkeeping.currently_waiting_customers);
     sim.bookkeeping.average_customers_waiting += sim.bookkeeping.currently_waiting_customers / SIMULATION_LENGTH;
     if sim.bookkeeping.max_customers_waiting < sim.bookkeeping.currently_waiting_customers{
         sim.bookkeeping.max_customers_waiting = sim.bookkeeping.currently_waiting_customers;
@@ -412,7 +2745,12 @@ fn poll_stats( mut sim : Simulation) -> Simulation {
     }
 
     // Trains deployed
-    sim.bookkeeping.average_trains_deployed += sim.line.trains_deployed / SIMULATION_LENGTH;
+    let mut trains_deployed = 0.0;
+    for line in &sim.lines {
+        trains_deployed += line.trains_deployed;
+    }
+    sim.bookkeeping.average_trains_deployed += trains_deployed / SIMULATION_LENGTH;
+    sim.bookkeeping.max_trains_deployed = sim.bookkeeping.max_trains_deployed.max(trains_deployed);
 
     sim.add_event(EventTypes::PollStats(), sim.time_elapsed + 1.0);
     return sim;
@@ -420,7 +2758,27 @@ fn poll_stats( mut sim : Simulation) -> Simulation {
 
 fn train_arrival(mut sim : Simulation, train_id: usize, station_id: usize) -> Simulation {
     
-    if PRINT_TRAIN_INFO { println!("{} -- Train {} ARRIVAL at {}", sim.time_elapsed, train_id, sim.line.id_to_name(station_id)); }
+    if sim.train_list[train_id].out_of_service { return sim; } // Waiting to be cleared off the line
+    if sim.train_list[train_id].pending_delay > 0.0 {
+        // Broke down on the way here, arrive once it has been fixed
+        let delay = sim.train_list[train_id].pending_delay;
+        sim.train_list[train_id].pending_delay = 0.0;
+        sim.add_event(EventTypes::TrainArrival(train_id, station_id), sim.time_elapsed + delay);
+        return sim;
+    }
+
+    let line_id = sim.train_list[train_id].line;
+    sim.log.write(LogCategory::Train(), LogLevel::Debug(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+        || format!("Train {} ARRIVAL at {}", train_id, sim.lines[line_id].id_to_name(station_id)));
+
+    sim.leave_single_track(train_id, station_id);
+
+    // Customers headed to a closed station get off one stop early
+    let next_station = if sim.train_list[train_id].direction == EASTWARD {station_id + 1} else {station_id.wrapping_sub(1)};
+    let next_closed = next_station < sim.lines[line_id].length() && sim.lines[line_id].is_closed(next_station, sim.time_elapsed);
+    let station_closed = sim.lines[line_id].is_closed(station_id, sim.time_elapsed);
+    let stops_here = !station_closed && sim.train_serves(train_id, station_id);
+    sim.log_arrival(train_id, station_id, stops_here);
 
     // TODO: PUT CUSTOMER DEPARTURE CODE WHERE WHEN THAT EXISTS!!!!
     // THIS CODE UNBOARDS ALL PASSENGERS CURRENTLY (REGARDLESS OF GOAL STATION)
@@ -431,41 +2789,60 @@ fn train_arrival(mut sim : Simulation, train_id: usize, station_id: usize) -> Si
     while customer_index > 0 { // NOTE: We work back-to_front to avoid ordering issues with removal
 
         // Remove any thing into
-        if sim.train_list[train_id].customer_list[customer_index - 1].end_at == station_id {
-            sim.train_list[train_id].customer_list.remove(customer_index - 1);
+        let end_at = sim.train_list[train_id].customer_list[customer_index - 1].end_at;
+        if (end_at == station_id && stops_here) || (end_at == next_station && next_closed && stops_here) {
+            let customer = sim.train_list[train_id].customer_list.remove(customer_index - 1);
             sim.train_list[train_id].riding_customers -= 1.0;
+            sim.train_list[train_id].car_riders[customer.car] -= 1.0;
             customer_count += 1;
-            sim.bookkeeping.total_customers_departed += 1.0;
+            sim.customer_alights(customer, station_id);
         }
         customer_index -= 1;
     }
 
-    if customer_count > 0 && PRINT_CUSTOMER_INFO {
-        println!("{} -- Train {} dropped off {} passengers at {}", sim.time_elapsed, train_id, customer_count, sim.line.id_to_name(station_id)); 
+    if customer_count > 0 {
+        sim.log.write(LogCategory::Customer(), LogLevel::Debug(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+            || format!("Train {} dropped off {} passengers at {}", train_id, customer_count, sim.lines[line_id].id_to_name(station_id)));
     }
 
     // Terminal station check
     if sim.train_list[train_id].at_station == 0 && sim.train_list[train_id].direction == WESTWARD {
         sim.train_list[train_id].disable();
-        if PRINT_TRAIN_INFO { println!("{} -- Train {} REACHED TERMINAL STATION (WESTWARD)", sim.time_elapsed, train_id); }
-        sim.line.trains_deployed -= 1.0;
-        sim.line.east_trains.push_back(train_id);
+        sim.log.write(LogCategory::Train(), LogLevel::Info(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+            || format!("Train {} REACHED TERMINAL STATION (WESTWARD)", train_id));
+        sim.lines[line_id].trains_deployed -= 1.0;
+        sim.lines[line_id].east_trains.push_back(train_id);
         return sim;
     }
-    else if sim.train_list[train_id].at_station == sim.line.length() - 1 && sim.train_list[train_id].direction == EASTWARD {
+    else if sim.train_list[train_id].at_station == sim.lines[line_id].length() - 1 && sim.train_list[train_id].direction == EASTWARD {
         sim.train_list[train_id].disable();
-        if PRINT_TRAIN_INFO { println!("{} -- Train {} REACHED TERMINAL STATION (WESTWARD)", sim.time_elapsed, train_id); }
-        sim.line.trains_deployed -= 1.0;
-        sim.line.west_trains.push_back(train_id);
+        sim.log.write(LogCategory::Train(), LogLevel::Info(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+            || format!("Train {} REACHED TERMINAL STATION (WESTWARD)", train_id));
+        sim.lines[line_id].trains_deployed -= 1.0;
+        sim.lines[line_id].west_trains.push_back(train_id);
         return sim;
     }
 
     sim.train_list[train_id].arrive_at(station_id);
-    
+
+    let mut dwell_time = if stops_here {STATION_DWELL_TIME} else {0.0}; // Closed and skipped stations are passed through without stopping
+    sim.train_list[train_id].passing_through = !stops_here;
+    if sim.train_list[train_id].turns_back_at(station_id) {
+        // End of a short turn, head back the way we came
+        sim.detrain_to_platform(train_id, station_id);
+        sim.log_departure(train_id);
+        sim.train_list[train_id].switch_direction();
+        sim.log_arrival(train_id, station_id, true); // Starts the trip back
+        sim.bookkeeping.short_turns += 1.0;
+        sim.train_list[train_id].passing_through = false;
+        dwell_time = TURNBACK_TIME;
+        sim.log.write(LogCategory::Train(), LogLevel::Info(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+            || format!("Train {} TURNING BACK at {}", train_id, sim.lines[line_id].id_to_name(station_id)));
+    }
     if sim.train_list[train_id].direction == EASTWARD {
-        sim.add_event(EventTypes::TrainDeparture(train_id, station_id + 1), sim.time_elapsed + 0.5);
+        sim.add_event(EventTypes::TrainDeparture(train_id, station_id + 1), sim.time_elapsed + dwell_time);
     } else { // WESTWARD
-        sim.add_event(EventTypes::TrainDeparture(train_id, station_id - 1), sim.time_elapsed + 0.5);
+        sim.add_event(EventTypes::TrainDeparture(train_id, station_id - 1), sim.time_elapsed + dwell_time);
     }
 
     return sim;
@@ -473,24 +2850,89 @@ fn train_arrival(mut sim : Simulation, train_id: usize, station_id: usize) -> Si
 
 fn train_departure(mut sim : Simulation, train_id: usize, station_id: usize) -> Simulation {
     
-    if PRINT_TRAIN_INFO {println!("{} -- Train {} DEPARTURE to {}", sim.time_elapsed, train_id, sim.line.id_to_name(station_id)); }
+    if sim.train_list[train_id].out_of_service { return sim; } // Waiting to be cleared off the line
+    if sim.train_list[train_id].pending_delay > 0.0 {
+        // Broke down at this station, leave once it has been fixed
+        let delay = sim.train_list[train_id].pending_delay;
+        sim.train_list[train_id].pending_delay = 0.0;
+        sim.add_event(EventTypes::TrainDeparture(train_id, station_id), sim.time_elapsed + delay);
+        return sim;
+    }
+
+    let line_id = sim.train_list[train_id].line;
+    let block_delay = sim.block_delay(line_id, sim.train_list[train_id].direction, station_id);
+    if block_delay > 0.0 {
+        // A failed train is in the way, hold here until it is fixed or cleared
+        sim.bookkeeping.incident_delay_minutes += sim.train_list[train_id].riding_customers * block_delay;
+        sim.add_event(EventTypes::TrainDeparture(train_id, station_id), sim.time_elapsed + block_delay);
+        sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(sim.train_list[train_id].at_station)),
+            || format!("Train {} HELD for {:.2} minutes by a failed train", train_id, block_delay));
+        return sim;
+    }
+
+    // Single tracked sections can only be used by one direction at a time
+    let direction = sim.train_list[train_id].direction;
+    let current_station = sim.train_list[train_id].at_station;
+    for section in sim.lines[line_id].single_track.iter_mut() {
+        if !section.entered_by(direction, current_station, sim.time_elapsed) { continue; }
+        if !section.can_enter(direction) {
+            section.waiting.push_back((train_id, station_id));
+            sim.bookkeeping.single_track_holds += 1.0;
+            sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(current_station)),
+                || format!("Train {} HELD at {} for single tracking", train_id, sim.lines[line_id].id_to_name(current_station)));
+            return sim;
+        }
+        section.direction = direction;
+        section.trains_inside.push(train_id);
+    }
+
+    // Track shared with another line only takes one train at a time
+    let segment = (sim.lines[line_id].network_ids[current_station], sim.lines[line_id].network_ids[station_id]);
+    if let Some(free_at) = sim.network.interlined_free_at.get(&segment).copied() {
+        if free_at > sim.time_elapsed {
+            sim.bookkeeping.interlined_holds += 1.0;
+            sim.add_event(EventTypes::TrainDeparture(train_id, station_id), free_at);
+            sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(current_station)),
+                || format!("Train {} HELD at {} for interlined track", train_id, sim.lines[line_id].id_to_name(current_station)));
+            return sim;
+        }
+        sim.network.interlined_free_at.insert(segment, sim.time_elapsed + INTERLINED_HEADWAY);
+    }
+
+    sim.log.write(LogCategory::Train(), LogLevel::Debug(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(current_station)),
+        || format!("Train {} DEPARTURE to {}", train_id, sim.lines[line_id].id_to_name(station_id)));
 
     // Get customers to board train
     let mut customer_count = 0;
-    let customers_missed;
     let train_station = sim.train_list[train_id].at_station;
+    let stops_here = !sim.train_list[train_id].passing_through;
+    if stops_here && sim.mean_patience.is_some() { sim.renege_customers(line_id, train_station); }
 
     let mut boarding_customer: Customer;
 
     // EASTWARD
     if sim.train_list[train_id].direction == EASTWARD {
-        customers_missed = sim.line.stations[train_station].west_customers.len();
-        
-        while !sim.line.stations[train_station].east_customers.is_empty() && sim.train_list[train_id].has_capacity() {
-            boarding_customer = sim.line.stations[train_station].east_customers.pop_front().expect("ERR: EMPTY EAST CUSTOMER LIST");
+        let mut position = 0;
+        while stops_here && position < sim.lines[line_id].stations[train_station].east_customers.len() && sim.train_list[train_id].has_capacity() {
+            let waiting_customer = &sim.lines[line_id].stations[train_station].east_customers[position];
+            if !sim.train_serves(train_id, waiting_customer.end_at) || waiting_customer.sat > sim.time_elapsed {
+                position += 1; // This train doesn't go where they want to, or they are still walking over from another line
+                continue;
+            }
+            boarding_customer = sim.lines[line_id].stations[train_station].east_customers.remove(position).expect("ERR: EMPTY EAST CUSTOMER LIST");
             boarding_customer.tbt = sim.time_elapsed;
+            boarding_customer.waited += boarding_customer.tbt - boarding_customer.sat;
+            sim.bookkeeping.record_pass_ups(sim.lines[line_id].network_ids[train_station], sim.time_elapsed, boarding_customer.times_left_behind);
+            sim.bookkeeping.station_wait[sim.lines[line_id].network_ids[train_station]] += boarding_customer.tbt - boarding_customer.sat;
+            boarding_customer.times_left_behind = 0;
 
             sim.bookkeeping.total_station_waiting_time += boarding_customer.tbt - boarding_customer.sat; // Total waiting time
+            if boarding_customer.transferring {
+                let network_station = sim.lines[line_id].network_ids[train_station];
+                sim.bookkeeping.station_transfers[network_station] += 1.0;
+                sim.bookkeeping.station_transfer_wait[network_station] += boarding_customer.tbt - boarding_customer.sat;
+                boarding_customer.transferring = false;
+            }
             if boarding_customer.tbt - boarding_customer.sat > sim.bookkeeping.max_station_waiting_time && sim.time_elapsed > 60.0{ // Max wait time check
                 sim.bookkeeping.max_station_waiting_time = boarding_customer.tbt - boarding_customer.sat;
                 sim.bookkeeping.max_station_waiting_time_t = sim.time_elapsed;
@@ -499,18 +2941,34 @@ fn train_departure(mut sim : Simulation, train_id: usize, station_id: usize) ->
             sim.bookkeeping.total_customers_boarded += 1.0; // Amount of customers boarded
             sim.bookkeeping.currently_waiting_customers -= 1.0;  // One less waiting
 
+            boarding_customer.car = sim.train_list[train_id].pick_car(boarding_customer.platform_position);
+            sim.train_list[train_id].car_riders[boarding_customer.car] += 1.0;
             sim.train_list[train_id].riding_customers += 1.0;
             sim.train_list[train_id].customer_list.push(boarding_customer);
             customer_count += 1;
         }
     } else { // WESTWARD
-        customers_missed = sim.line.stations[train_station].east_customers.len();
-
-        while !sim.line.stations[train_station].west_customers.is_empty() && sim.train_list[train_id].has_capacity() {
-            boarding_customer = sim.line.stations[train_station].west_customers.pop_front().expect("ERR: EMPTY EAST CUSTOMER LIST");
+        let mut position = 0;
+        while stops_here && position < sim.lines[line_id].stations[train_station].west_customers.len() && sim.train_list[train_id].has_capacity() {
+            let waiting_customer = &sim.lines[line_id].stations[train_station].west_customers[position];
+            if !sim.train_serves(train_id, waiting_customer.end_at) || waiting_customer.sat > sim.time_elapsed {
+                position += 1; // This train doesn't go where they want to, or they are still walking over from another line
+                continue;
+            }
+            boarding_customer = sim.lines[line_id].stations[train_station].west_customers.remove(position).expect("ERR: EMPTY EAST CUSTOMER LIST");
             boarding_customer.tbt = sim.time_elapsed;
+            boarding_customer.waited += boarding_customer.tbt - boarding_customer.sat;
+            sim.bookkeeping.record_pass_ups(sim.lines[line_id].network_ids[train_station], sim.time_elapsed, boarding_customer.times_left_behind);
+            sim.bookkeeping.station_wait[sim.lines[line_id].network_ids[train_station]] += boarding_customer.tbt - boarding_customer.sat;
+            boarding_customer.times_left_behind = 0;
 
             sim.bookkeeping.total_station_waiting_time += boarding_customer.tbt - boarding_customer.sat; // Total waiting time
+            if boarding_customer.transferring {
+                let network_station = sim.lines[line_id].network_ids[train_station];
+                sim.bookkeeping.station_transfers[network_station] += 1.0;
+                sim.bookkeeping.station_transfer_wait[network_station] += boarding_customer.tbt - boarding_customer.sat;
+                boarding_customer.transferring = false;
+            }
             if boarding_customer.tbt - boarding_customer.sat > sim.bookkeeping.max_station_waiting_time && sim.time_elapsed > 60.0{ // Max wait time check
                 sim.bookkeeping.max_station_waiting_time = boarding_customer.tbt - boarding_customer.sat;
                 sim.bookkeeping.max_station_waiting_time_t = sim.time_elapsed;
@@ -519,186 +2977,1006 @@ fn train_departure(mut sim : Simulation, train_id: usize, station_id: usize) ->
             sim.bookkeeping.total_customers_boarded += 1.0; // Amount of customer sboarded
             sim.bookkeeping.currently_waiting_customers -= 1.0; // One less waiting
 
+            boarding_customer.car = sim.train_list[train_id].pick_car(boarding_customer.platform_position);
+            sim.train_list[train_id].car_riders[boarding_customer.car] += 1.0;
             sim.train_list[train_id].riding_customers += 1.0;
             sim.train_list[train_id].customer_list.push(boarding_customer);
             customer_count += 1;
         }
     }
 
-    if customer_count > 0 && PRINT_CUSTOMER_INFO {
-        println!("{} -- Train {} picked up {} passengers from {}", sim.time_elapsed, train_id, customer_count, sim.line.id_to_name(train_station)); 
+    if stops_here && sim.train_list[train_id].riding_customers > 0.0 {
+        let train = &sim.train_list[train_id];
+        sim.bookkeeping.record_car_loads(&train.car_riders, train.capacity / train.cars as f32);
+    }
+
+    // Anyone still waiting who could have taken this train was left behind because it was full
+    let mut customers_left_behind = 0;
+    if stops_here && !sim.train_list[train_id].has_capacity() {
+        customers_left_behind = sim.leave_behind(train_id, train_station);
+    }
+
+    if customer_count > 0 {
+        sim.log.write(LogCategory::Customer(), LogLevel::Debug(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(train_station)),
+            || format!("Train {} picked up {} passengers from {}", train_id, customer_count, sim.lines[line_id].id_to_name(train_station)));
     }
-    if customers_missed > 0 && PRINT_CUSTOMER_INFO {
-        println!("{} -- Train {} missed {} passengers from {}", sim.time_elapsed, train_id, customers_missed, sim.line.id_to_name(train_station)); 
+    if customers_left_behind > 0 {
+        sim.log.write(LogCategory::Customer(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(train_station)),
+            || format!("Train {} left behind {} passengers at {}", train_id, customers_left_behind, sim.lines[line_id].id_to_name(train_station)));
     }
 
-    let train_travel_time: f32;
-    if sim.train_list[train_id].direction == EASTWARD {train_travel_time = sim.line.inter_station_traveltimes[sim.train_list[train_id].at_station];}
-    else {train_travel_time = sim.line.inter_station_traveltimes[sim.train_list[train_id].at_station - 1];}
+    let from_stop = !sim.train_list[train_id].passing_through;
+    let to_stop = !sim.lines[line_id].is_closed(station_id, sim.time_elapsed) && sim.train_serves(train_id, station_id);
+    let mut train_travel_time: f32;
+    if sim.train_list[train_id].direction == EASTWARD {train_travel_time = sim.lines[line_id].inter_station_traveltimes[sim.train_list[train_id].at_station];}
+    else {train_travel_time = sim.lines[line_id].inter_station_traveltimes[sim.train_list[train_id].at_station - 1];}
+    train_travel_time += sim.fleet.types[sim.train_list[train_id].type_id].travel_time_adjustment();
+    if sim.train_list[train_id].passing_through {
+        // No braking for the station we skipped or accelerating away from it
+        train_travel_time -= LINE_SPEED / sim.fleet.types[sim.train_list[train_id].type_id].acceleration / 60.0;
+        sim.train_list[train_id].passing_through = false;
+    }
+
+    let train = &sim.train_list[train_id];
+    let cars = train.cars as f32;
+    for car in 0..train.car_riders.len() {
+        sim.bookkeeping.record_crowding(train.car_riders[car], train.seats / cars, train.standing_area / cars, train_travel_time);
+    }
 
     let is_full = sim.train_list[train_id].poll_usage();
-    if is_full && PRINT_FULL_TIMES {
-        println!("-- Train {} is full at time {}! --", train_id, sim.time_elapsed)
+    let cur_cap_percent = sim.train_list[train_id].riding_customers / sim.train_list[train_id].capacity;
+    for unit_index in 0..sim.train_list[train_id].coupled_units.len() {
+        let unit = sim.train_list[train_id].coupled_units[unit_index];
+        sim.train_list[unit].record_usage(cur_cap_percent);
+    }
+
+    let segment_distance : f32;
+    if sim.train_list[train_id].direction == EASTWARD {segment_distance = sim.lines[line_id].inter_station_distances[sim.train_list[train_id].at_station];}
+    else {segment_distance = sim.lines[line_id].inter_station_distances[sim.train_list[train_id].at_station - 1];}
+    sim.bookkeeping.train_km += segment_distance;
+    sim.bookkeeping.car_km += segment_distance * sim.train_list[train_id].cars as f32;
+    let segment = if sim.train_list[train_id].direction == EASTWARD {sim.train_list[train_id].at_station} else {sim.train_list[train_id].at_station - 1};
+    sim.record_energy(train_id, segment, train_travel_time, from_stop, to_stop);
+    let od_stations = sim.bookkeeping.od_stations;
+    let (from, to) = (sim.lines[line_id].network_ids[sim.train_list[train_id].at_station], sim.lines[line_id].network_ids[station_id]);
+    sim.bookkeeping.segment_load[from * od_stations + to] += sim.train_list[train_id].riding_customers;
+    if is_full {
+        sim.log.write(LogCategory::Full(), LogLevel::Info(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(sim.train_list[train_id].at_station)),
+            || format!("-- Train {} is full! --", train_id));
     }
 
+    sim.log_departure(train_id);
     sim.train_list[train_id].leave_to(station_id);
     sim.add_event(EventTypes::TrainArrival(train_id, station_id), sim.time_elapsed + train_travel_time);
     
     return sim;
 }
 
-fn release_train(mut sim : Simulation, direction : i8) -> Simulation {
-    // Puts a train on the tracks going the given direction
+fn train_failure(mut sim : Simulation, train_id: usize, trip: u32) -> Simulation {
+    // Breaks the given train down wherever it is, trains behind it can't pass until it is fixed or cleared off the line
 
-    let mut train_id = 0;
+    if !sim.train_list[train_id].active || sim.train_list[train_id].trip != trip || sim.train_list[train_id].out_of_service {
+        return sim; // Failure was drawn for a trip that is already over
+    }
 
-    if direction == EASTWARD { // RELEASE ONTO STATION 0
-        let mut is_train = true;
-        match sim.line.release_eastward() {
-            Some(s) => train_id = s,
-            None => is_train = false
-        }
+    let train_type = &sim.fleet.types[sim.train_list[train_id].type_id];
+    let mean = train_type.mean_repair_time;
+    let repair_dist = LogNormal::new(mean.ln() - (REPAIR_TIME_SIGMA * REPAIR_TIME_SIGMA / 2.0), REPAIR_TIME_SIGMA).unwrap();
+    let repair_time = repair_dist.sample(sim.failure_rng.as_mut().expect("ERR: FAILURE WITHOUT A FAILURE RNG"));
+
+    let line_id = sim.train_list[train_id].line;
+    let station_id = sim.train_list[train_id].at_station;
+    let riders = sim.train_list[train_id].riding_customers;
+    sim.bookkeeping.incidents += 1.0;
+
+    let blocked_for : f32;
+    if repair_time <= MAX_REPAIR_IN_PLACE {
+        // Fixed where it stands
+        sim.train_list[train_id].pending_delay += repair_time;
+        sim.bookkeeping.trains_recovered += 1.0;
+        blocked_for = repair_time;
+        sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+            || format!("Train {} FAILED near {}, fixed in {:.2} minutes", train_id, sim.lines[line_id].id_to_name(station_id), repair_time));
+    } else {
+        // Needs the depot, clear it off the line
+        sim.train_list[train_id].out_of_service = true;
+        sim.bookkeeping.trains_pulled += 1.0;
+        blocked_for = RESCUE_TIME;
+        sim.add_event(EventTypes::TrainRescue(train_id), sim.time_elapsed + RESCUE_TIME);
+        sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+            || format!("Train {} FAILED near {}, pulling it out of service", train_id, sim.lines[line_id].id_to_name(station_id)));
+    }
+    sim.bookkeeping.incident_delay_minutes += riders * blocked_for;
 
-        if is_train {
-            // Put train on first station
-            sim.train_list[train_id].active = true;
-            sim.train_list[train_id].direction = direction;
-            sim.train_list[train_id].at_station = 0;
-            sim.add_event(EventTypes::TrainArrival(train_id, 0), sim.time_elapsed + 0.5);
-            if PRINT_TRAIN_INFO {println!("{} -- Train {} RELEASED going EAST", sim.time_elapsed, train_id);}
-        } else {
-            if PRINT_TRAIN_INFO {println!("{} -- UNABLE TO RELEASE TRAIN EASTWARD!", sim.time_elapsed);}
-        }
+    let blocked_until = sim.time_elapsed + blocked_for;
+    if sim.train_list[train_id].direction == EASTWARD {
+        sim.lines[line_id].east_blocked_until[station_id] = sim.lines[line_id].east_blocked_until[station_id].max(blocked_until);
+    } else {
+        sim.lines[line_id].west_blocked_until[station_id] = sim.lines[line_id].west_blocked_until[station_id].max(blocked_until);
     }
 
-    else if direction == WESTWARD { // RELEASE ONTO LAST STATION
-        let mut is_train = true;
-        match sim.line.release_westward() {
-            Some(s) => train_id = s,
-            None => is_train = false
-        }
+    return sim;
+}
 
-        if is_train {
-            // Put train on last station
-            sim.train_list[train_id].active = true;
-            sim.train_list[train_id].direction = direction;
-            sim.train_list[train_id].at_station = sim.line.length() - 1;
-            sim.add_event(EventTypes::TrainArrival(train_id, sim.line.length() - 1), sim.time_elapsed + TRAIN_STOP_TIME);
-            if PRINT_TRAIN_INFO {println!("{} -- Train {} RELEASED going WEST", sim.time_elapsed, train_id);}
-        } else {
-            if PRINT_TRAIN_INFO {println!("{} -- UNABLE TO RELEASE TRAIN WESTWARD!", sim.time_elapsed);}
-        }
+fn train_rescue(mut sim : Simulation, train_id: usize) -> Simulation {
+    // Clears a failed train off the line and sends it to the depot
+    // Its passengers are let off at the station it was at (or headed to) and have to wait for the next train
+
+    let station_id = sim.train_list[train_id].at_station;
+    sim.detrain_to_platform(train_id, station_id);
+    sim.log_departure(train_id); // Its trip ends here if it failed while stopped
+    sim.train_list[train_id].disable();
+    let line_id = sim.train_list[train_id].line;
+    sim.lines[line_id].trains_deployed -= 1.0;
+    sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, Some(train_id), Some(sim.lines[line_id].id_to_name(station_id)),
+        || format!("Train {} CLEARED from the line", train_id));
+
+    return sim;
+}
+
+fn station_closure(mut sim : Simulation, line_id: usize, station_id: usize) -> Simulation {
+    // Closes a station, everyone waiting on its platforms has to go somewhere else
+    let mut waiting: Vec<Customer> = sim.lines[line_id].stations[station_id].east_customers.drain(..).collect();
+    waiting.extend(sim.lines[line_id].stations[station_id].west_customers.drain(..));
+    waiting.extend(sim.lines[line_id].stations[station_id].concourse.drain(..));
+
+    sim.log.write(LogCategory::Arrival(), LogLevel::Warn(), sim.time_elapsed, None, Some(sim.lines[line_id].id_to_name(station_id)),
+        || format!("{} CLOSED, {} customers sent away", sim.lines[line_id].id_to_name(station_id), waiting.len()));
+    for customer in waiting {
+        sim.divert_customer(customer, true);
     }
 
+    return sim;
+}
+
+fn release_train(mut sim : Simulation, line_id : usize, direction : i8) -> Simulation {
+    // Puts a train on the given line's tracks going the given direction
+
+    sim.launch_train(line_id, direction);
+
     // Arrival types
     match sim.dispatch_type {
-        DispatchTypes::Constant(lambda) => sim.add_event(EventTypes::TrainRelease(direction), sim.time_elapsed + lambda),
-        DispatchTypes::TransLink() => sim.add_event(EventTypes::TrainRelease(direction), sim.time_elapsed + sim.translink_time_sampler()),
-        DispatchTypes::TimeBased(offset) => sim.add_event(EventTypes::TrainRelease(direction), sim.time_elapsed + sim.timebased_time_sampler(offset)),
-        DispatchTypes::PopBased(multi) => sim.add_event(EventTypes::TrainRelease(direction), sim.time_elapsed + sim.population_sampler(multi)),
-        _ => println!("Error: Somehow a dispatch type has not been defined..."),
+        DispatchTypes::Constant(lambda) => sim.add_event(EventTypes::TrainRelease(line_id, direction), sim.time_elapsed + lambda),
+        DispatchTypes::TransLink() => sim.add_event(EventTypes::TrainRelease(line_id, direction), sim.time_elapsed + sim.translink_time_sampler()),
+        DispatchTypes::TimeBased(offset) => sim.add_event(EventTypes::TrainRelease(line_id, direction), sim.time_elapsed + sim.timebased_time_sampler(offset)),
+        DispatchTypes::PopBased(multi) => sim.add_event(EventTypes::TrainRelease(line_id, direction), sim.time_elapsed + sim.population_sampler(line_id, multi)),
+        DispatchTypes::Timetable() => (), // Timetabled trains are released by ScheduledRelease events
     }
 
     return sim;
 }
 
+fn scheduled_release(mut sim : Simulation, line_id : usize, direction : i8, departure : usize) -> Simulation {
+    // Releases a timetabled departure, or tries again shortly if there is no train ready at the terminal
+    // A departure still waiting for a train when the next one is due is cancelled
+    let direction_index = if direction == EASTWARD {0} else {1};
+    let scheduled = sim.lines[line_id].timetable[direction_index][departure];
+    let next = sim.lines[line_id].timetable[direction_index].get(departure + 1).copied();
+
+    match sim.launch_train(line_id, direction) {
+        Some(coupling_delay) => {
+            let lateness = sim.time_elapsed + coupling_delay - scheduled;
+            sim.bookkeeping.record_scheduled_departure(Some(lateness));
+            if lateness > LATE_DEPARTURE_TOLERANCE {
+                sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, None, None,
+                    || format!("-- Line {} departure going {} left {:.2} minutes late --", line_id, direction, lateness));
+            }
+        },
+        None => {
+            if next.is_none_or(|next| sim.time_elapsed + TIMETABLE_RETRY < next) {
+                sim.add_event(EventTypes::ScheduledRelease(line_id, direction, departure), sim.time_elapsed + TIMETABLE_RETRY);
+                return sim;
+            }
+            sim.bookkeeping.record_scheduled_departure(None);
+            sim.log.write(LogCategory::Train(), LogLevel::Warn(), sim.time_elapsed, None, None,
+                || format!("CANCELLED DEPARTURE SCHEDULED FOR {} ON LINE {}", format_clock_time(scheduled), line_id));
+        },
+    }
+
+    if let Some(next) = next {
+        sim.add_event(EventTypes::ScheduledRelease(line_id, direction, departure + 1), next.max(sim.time_elapsed));
+    }
+    return sim;
+}
 
-fn customer_arrival(mut sim : Simulation, station_id: usize) -> Simulation {
-    // Has a customer arrive at the given station with a random destination station and 
+fn customer_arrival(mut sim : Simulation, network_station: usize) -> Simulation {
+    // Has a customer arrive at the given network station with a random destination station and 
     // Adds a new customer arrival event using the given RNG var in the sim object
 
     // Generate target station
-    let target_gen = rand::distributions::Uniform::new(0, sim.line.length());
-    let mut target_station = station_id;
-    while station_id == target_station {
-        target_station = target_gen.sample(&mut sim.customer_iat);
+    let target_gen = rand::distributions::Uniform::new(0, sim.network.length());
+    let mut target_station = network_station;
+    while network_station == target_station {
+        target_station = target_gen.sample(&mut sim.destination_rng);
     }
 
-    let new_customer = Customer {sat : sim.time_elapsed, tbt: 0.0, tet: 0.0, 
-        start_at: station_id, end_at : target_station, assist : false};
+    let (itinerary, cost, cheapest) = sim.network.plan_trip(network_station, target_station, &mut sim.route_rng);
+    let mut new_customer = Customer::new(sim.time_elapsed, itinerary, network_station, target_station);
+    sim.bookkeeping.planned_trip_cost += cost;
+    if !cheapest {sim.bookkeeping.customers_off_cheapest_route += 1.0;}
+    let (line_id, station_id) = (new_customer.line, new_customer.start_at);
+    if let Some(mean) = sim.mean_patience {
+        let patience_dist = LogNormal::new(mean.ln() - (PATIENCE_SIGMA * PATIENCE_SIGMA / 2.0), PATIENCE_SIGMA).unwrap();
+        new_customer.patience = patience_dist.sample(&mut sim.patience_rng);
+    }
+    new_customer.platform_position = sim.pick_platform_position(line_id, station_id);
+    let balks = sim.balk_queue_length.is_some_and(|length| sim.lines[line_id].stations[station_id].queue_length(new_customer.get_direction()) >= length);
     
-    sim.line.stations[station_id].add_customer(new_customer);
-
     // Update bookkeeping
     sim.bookkeeping.total_customers += 1.0;
-    sim.bookkeeping.currently_waiting_customers += 1.0;
-
-    // Query new customer arrival event
-    let iat = rand_distr::Exp::new(sim.line.stations[station_id].get_true_iat(sim.time_elapsed)).unwrap();
-    let new_iat = iat.sample(&mut sim.customer_iat);
-    sim.add_event(EventTypes::CustomerArrival(station_id), sim.time_elapsed + new_iat);
 
-    if PRINT_ARRIVAL_INFO {
-        println!("{} -- Added customer to station {} (Goal: {})", sim.time_elapsed, sim.line.id_to_name(station_id), sim.line.id_to_name(target_station));
+    if balks {
+        // The platform is too crowded, go some other way
+        sim.bookkeeping.customers_balked += 1.0;
+    } else if sim.lines[line_id].is_closed(station_id, sim.time_elapsed) {
+        sim.divert_customer(new_customer, false);
+    } else {
+        if sim.lines[line_id].stations[station_id].enter_through_gates(new_customer) {
+            sim.bookkeeping.customers_held_at_gates += 1.0;
+        }
+        sim.bookkeeping.currently_waiting_customers += 1.0;
     }
 
+    // Query new customer arrival event
+    let (home_line, home_station) = sim.network.home_stations[network_station];
+    let rate = sim.lines[home_line].stations[home_station].get_true_iat(sim.time_elapsed);
+    let arrival_rng = &mut sim.arrival_rngs[network_station];
+    let new_iat = match sim.arrival_draws {
+        ArrivalDraws::Ziggurat() => rand_distr::Exp::new(rate).unwrap().sample(arrival_rng),
+        ArrivalDraws::Inverse() => -(1.0 - rand::Rng::gen::<f32>(arrival_rng)).ln() / rate,
+        ArrivalDraws::Mirrored() => -rand::Rng::gen::<f32>(arrival_rng).max(f32::MIN_POSITIVE).ln() / rate,
+    };
+    sim.add_event(EventTypes::CustomerArrival(network_station), sim.time_elapsed + new_iat);
+
+    sim.log.write(LogCategory::Arrival(), LogLevel::Debug(), sim.time_elapsed, None, Some(sim.network.id_to_name(network_station)),
+        || format!("Added customer to station {} (Goal: {})", sim.network.id_to_name(network_station), sim.network.id_to_name(target_station)));
+
     return sim
 }
 
-fn main() {
-    // Main simulation loop
+fn take_option(args : &mut Vec<String>, name : &str) -> Option<String> {
+    // Removes "--name value" from the arguements and returns the value if it was given
+    let position = args.iter().position(|arg| arg == name)?;
+    if position + 1 >= args.len() {
+        args.remove(position);
+        return None;
+    }
+    let value = args.remove(position + 1);
+    args.remove(position);
+    return Some(value);
+}
 
+fn read_coupling_schedule(path : &str) -> Result<Vec<(f32, f32, u8)>, String> {
+    // Reads a coupling schedule, one window per line: <from minute>,<to minute>,<target cars>
+    // Blank lines and lines starting with # are ignored
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read coupling schedule {}: {}", path, e))?;
+    let mut windows = Vec::new();
 
-    // Get command line arguements
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        println!("ERROR: Please provide the following arguements\n<seed> <constant|timebased|popbased|translink> <parameter>");
-        return
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 3 {
+            return Err(format!("Coupling schedule line {} should have 3 fields, found {}", line_number + 1, fields.len()));
+        }
+        let bad_line = || format!("Coupling schedule line {} is invalid", line_number + 1);
+        let from : f32 = fields[0].parse().map_err(|_| bad_line())?;
+        let to : f32 = fields[1].parse().map_err(|_| bad_line())?;
+        let cars : u8 = fields[2].parse().map_err(|_| bad_line())?;
+        windows.push((from, to, cars));
     }
+    return Ok(windows);
+}
 
-    let seed : u64 = args[1].parse().unwrap();
+fn take_flag(args : &mut Vec<String>, name : &str) -> bool {
+    // Removes "--name" from the arguements and returns whether it was given
+    match args.iter().position(|arg| arg == name) {
+        Some(position) => {
+            args.remove(position);
+            return true;
+        },
+        None => return false,
+    }
+}
 
-    let dispatch_type : DispatchTypes;
-    let parameter : f32;
-    if args.len() == 3 {
-        // Default parameters
-        parameter = match args[2].to_lowercase().as_str() {
-            "constant" => 6.0, // How many times a second (default is six seconds)
-            "timebased" => 0.0, // Offset added to time function (default is none)
-            "popbased" => 1.0, // Multiplier to population function (default is none)
-            _ => 0.0,
+fn read_disruptions(path : &str, lines : &[Line]) -> Result<Vec<Disruption>, String> {
+    // Reads a disruption schedule, one disruption per line:
+    // close,<station>,<from minute>,<to minute>
+    // single,<first station>,<last station>,<from minute>,<to minute>
+    // Blank lines and lines starting with # are ignored
+    // A disruption applies to every line serving the stations named
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read disruption file {}: {}", path, e))?;
+    let mut disruptions = Vec::new();
+
+    for (line_number, text) in contents.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') { continue; }
+
+        let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+        let bad_line = || format!("Disruption file line {} is invalid", line_number + 1);
+        let unknown_station = || format!("Disruption file line {} has an unknown station: {}", line_number + 1, fields[1..fields.len() - 2].join(" / "));
+        let count_before = disruptions.len();
+
+        match (fields[0].to_lowercase().as_str(), fields.len()) {
+            ("close", 4) => {
+                let from : f32 = fields[2].parse().map_err(|_| bad_line())?;
+                let to : f32 = fields[3].parse().map_err(|_| bad_line())?;
+                for line_id in 0..lines.len() {
+                    if let Some(station_id) = lines[line_id].name_to_id(fields[1]) {
+                        disruptions.push(Disruption::StationClosure(line_id, station_id, from, to));
+                    }
+                }
+            },
+            ("single", 5) => {
+                let from : f32 = fields[3].parse().map_err(|_| bad_line())?;
+                let to : f32 = fields[4].parse().map_err(|_| bad_line())?;
+                for line_id in 0..lines.len() {
+                    if let (Some(a), Some(b)) = (lines[line_id].name_to_id(fields[1]), lines[line_id].name_to_id(fields[2])) {
+                        if a == b { return Err(bad_line()); }
+                        disruptions.push(Disruption::SingleTrack(line_id, a.min(b), a.max(b), from, to));
+                    }
+                }
+            },
+            _ => return Err(bad_line()),
+        }
+        if disruptions.len() == count_before { return Err(unknown_station()); }
+    }
+    return Ok(disruptions);
+}
+
+fn json_escape(text : &str) -> String {
+    return text.replace('\\', "\\\\").replace('"', "\\\"");
+}
+
+fn parse_trace_record(text : &str) -> Option<HashMap<String, String>> {
+    // Reads one flat JSON object from the event trace into its keys and values (strings unquoted)
+    let mut fields = HashMap::new();
+    fn read_string(chars : &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+        if chars.next()? != '"' { return None; }
+        let mut value = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(value),
+                '\\' => value.push(chars.next()?),
+                c => value.push(c),
+            }
+        }
+    }
+
+    let mut chars = text.trim().strip_prefix('{')?.strip_suffix('}')?.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) { chars.next(); }
+        if chars.peek().is_none() { return Some(fields); }
+        let key = read_string(&mut chars)?;
+        if chars.next()? != ':' { return None; }
+        let value = if chars.peek() == Some(&'"') {
+            read_string(&mut chars)?
+        } else {
+            let mut value = String::new();
+            while chars.peek().is_some_and(|c| *c != ',') { value.push(chars.next()?); }
+            value.trim().to_string()
         };
-        println!("Useing default parameter: {}", parameter);
-        
-    } else {
-        parameter = args[3].parse().unwrap();
+        fields.insert(key, value);
     }
+}
 
-    if args[2].to_lowercase() == "constant" {
-        dispatch_type = DispatchTypes::Constant(parameter);
-    } else if args[2].to_lowercase() == "timebased" {
-        dispatch_type = DispatchTypes::TimeBased(parameter);
-    } else if args[2].to_lowercase() == "popbased" {
-        dispatch_type = DispatchTypes::PopBased(parameter);
-    } else if args[2].to_lowercase() == "translink" {
-        dispatch_type = DispatchTypes::TransLink();
-    } else {
-        println!("INVALID DISPATCH METHOD, USE ONE OF THE FOLLOWING: <constant|timebased|popbased|translink>");
-        return
+fn parse_time_window(text : &str) -> Option<(f32, f32)> {
+    // Reads <HH:MM>,<HH:MM> into minutes since the simulation started, kept within the simulated day
+    let times: Vec<Option<f32>> = text.split(',').map(parse_clock_time).collect();
+    return match times[..] {
+        [Some(from), Some(to)] if from < to => Some((from.max(0.0), to.min(SIMULATION_LENGTH))),
+        _ => None,
+    };
+}
+
+fn parse_trace_time(text : &str) -> Option<f32> {
+    // Times can be given as clock times or as minutes since the simulation started
+    if text.contains(':') { return parse_clock_time(text); }
+    return text.parse().ok();
+}
+
+fn inspect_trace(mut args : Vec<String>) -> Result<(), String> {
+    // Prints the events of a trace written with --trace, or the state the simulation was in at a given time
+    let train_filter = take_option(&mut args, "--train");
+    let station_filter = take_option(&mut args, "--station");
+    let read_time = |value : Option<String>, name : &str| -> Result<Option<f32>, String> {
+        return match value {
+            Some(text) => parse_trace_time(&text).map(Some).ok_or(format!("{} needs a clock time (HH:MM) or minutes, not {}", name, text)),
+            None => Ok(None),
+        };
+    };
+    let from = read_time(take_option(&mut args, "--from"), "--from")?.unwrap_or(f32::MIN);
+    let to = read_time(take_option(&mut args, "--to"), "--to")?.unwrap_or(f32::MAX);
+    let at = read_time(take_option(&mut args, "--at"), "--at")?;
+    let path = args.get(2).ok_or("inspect needs the trace file to read")?;
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read trace {}: {}", path, e))?;
+
+    let matches = |record : &HashMap<String, String>| -> bool {
+        if train_filter.as_ref().is_some_and(|train| record.get("train") != Some(train)) { return false; }
+        if station_filter.as_ref().is_some_and(|station| record.get("station") != Some(station)) { return false; }
+        return true;
+    };
+
+    let mut trains: collections::BTreeMap<usize, HashMap<String, String>> = collections::BTreeMap::new(); // Last known state of each train
+    let mut stations: collections::BTreeMap<String, String> = collections::BTreeMap::new(); // Last known number waiting at each station
+    for (line_number, text) in contents.lines().enumerate() {
+        if text.trim().is_empty() { continue; }
+        let record = parse_trace_record(text).ok_or(format!("Trace line {} is invalid", line_number + 1))?;
+        let time : f32 = record.get("time").and_then(|time| time.parse().ok()).ok_or(format!("Trace line {} has no time", line_number + 1))?;
+
+        match at {
+            Some(at) => {
+                if time > at { break; }
+                if let (Some(station), Some(waiting)) = (record.get("station"), record.get("waiting")) {
+                    stations.insert(station.clone(), waiting.clone());
+                }
+                for (key, waiting) in record.iter() {
+                    if let Some(station) = key.strip_prefix("waiting@") { stations.insert(String::from(station), waiting.clone()); }
+                }
+                if let Some(train_id) = record.get("train").and_then(|train| train.parse().ok()) { trains.insert(train_id, record); }
+            },
+            None => {
+                if time < from || time > to || !matches(&record) { continue; }
+                let mut details = Vec::new();
+                for key in ["train", "line", "station", "next_station", "direction", "moving", "riders", "waiting", "trip", "departure", "trains_deployed", "total_waiting"] {
+                    if let Some(value) = record.get(key) { details.push(format!("{}={}", key, value)); }
+                }
+                print!("{} {} {}\n", format_clock_time(time), record.get("event").map(|event| event.as_str()).unwrap_or("?"), details.join(" "));
+            },
+        }
     }
 
-    // Initalize
+    if let Some(at) = at {
+        println!("State at {}:", format_clock_time(at));
+        let total_waiting : usize = stations.values().filter_map(|waiting| waiting.parse::<usize>().ok()).sum();
+        print!("    CUSTOMERS WAITING: {}\n", total_waiting);
+        println!("Trains in service:");
+        for (train_id, train) in trains.iter() {
+            if train.get("active").map(|active| active.as_str()) != Some("true") || !matches(train) { continue; }
+            let heading = if train.get("direction").map(|direction| direction.as_str()) == Some("1") {"EAST"} else {"WEST"};
+            let place = match train.get("next_station") {
+                Some(next_station) => format!("LEFT {} HEADED TO {}", train["station"], next_station),
+                None => format!("AT {}", train["station"]),
+            };
+            let failed = if train.get("out_of_service").map(|failed| failed.as_str()) == Some("true") {" (OUT OF SERVICE)"} else {""};
+            print!("    TRAIN {} ({}): {} {}, {} RIDERS{}\n", train_id, train["line"], heading, place, train["riders"], failed);
+        }
+        println!("Customers waiting by station:");
+        for (station, waiting) in stations.iter() {
+            if station_filter.as_ref().is_some_and(|name| name != station) { continue; }
+            print!("    {}: {}\n", station, waiting);
+        }
+    }
+    return Ok(());
+}
+
+fn parse_clock_time(text : &str) -> Option<f32> {
+    // Turns HH:MM or HH:MM:SS into minutes since the simulation started, hours past 24 are the next morning (as in GTFS)
+    let parts: Vec<f32> = text.trim().split(':').map(|part| part.parse::<f32>()).collect::<Result<Vec<f32>, _>>().ok()?;
+    if parts.len() < 2 || parts.len() > 3 { return None; }
+    let seconds = if parts.len() == 3 {parts[2]} else {0.0};
+    return Some((parts[0] * 60.0) + parts[1] + (seconds / 60.0) - SERVICE_START);
+}
+
+fn share(total : f32, count : f32) -> f32 {
+    // Average that comes out as 0 instead of NaN when there was nothing to average over
+    return if count > 0.0 {total / count} else {0.0};
+}
+
+fn standing_band(standing : f32, standing_area : f32) -> usize {
+    // Comfort band standees are in: 1 = comfortable, 2 = crowded, 3 = crush loaded
+    let density = if standing_area > 0.0 {standing / standing_area} else {0.0};
+    return if density <= COMFORTABLE_STANDING_DENSITY {1} else if density <= CROWDED_STANDING_DENSITY {2} else {3};
+}
+
+fn crowding_multiplier(riders : f32, seats : f32, standing_area : f32) -> f32 {
+    // How much longer a minute on a train carrying this many riders feels, averaged over the riders
+    if riders <= 0.0 { return CROWDING_MULTIPLIERS[0]; }
+    let seated = riders.min(seats);
+    let standing = riders - seated;
+    return ((seated * CROWDING_MULTIPLIERS[0]) + (standing * CROWDING_MULTIPLIERS[standing_band(standing, standing_area)])) / riders;
+}
+
+fn hour_label(hour : usize) -> String {
+    // Clock time an hour bucket of the per-hour stats starts at, e.g. 05:00 for the first one
+    return format_clock_time(hour as f32 * 60.0)[..5].to_string();
+}
+
+fn format_clock_time(minutes : f32) -> String {
+    // Turns minutes since the simulation started into HH:MM:SS, hours past 24 are kept for the next morning (as in GTFS)
+    let seconds = ((minutes + SERVICE_START) * 60.0).round() as u32;
+    return format!("{:02}:{:02}:{:02}", seconds / 3600, (seconds / 60) % 60, seconds % 60);
+}
+
+fn load_colour(load_factor : f32) -> String {
+    // Green for an empty train through yellow to red for a full one
+    let hue = 120.0 * (1.0 - load_factor.clamp(0.0, 1.0));
+    return format!("hsl({:.0},85%,42%)", hue);
+}
+
+fn xml_escape(text : &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+fn svg_line_chart(values : &[f32], label : &str) -> String {
+    // Plots one value per simulated minute, with the clock time along the bottom
+    let (width, height, left, bottom) = (900.0, 260.0, 60.0, 30.0);
+    let plot_width = width - left - 20.0;
+    let plot_height = height - bottom - 20.0;
+    let largest = values.iter().cloned().fold(1.0, f32::max);
+    let x = |minute : f32| left + minute * plot_width / SIMULATION_LENGTH;
+    let y = |value : f32| 20.0 + plot_height - (value * plot_height / largest);
+
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">\n", width, height);
+    for step in 0..5 {
+        let value = largest * step as f32 / 4.0;
+        svg += &format!("<line x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\" stroke=\"#ddd\"/>\n", left, y(value), left + plot_width, y(value));
+        svg += &format!("<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\" dominant-baseline=\"middle\">{:.0}</text>\n", left - 6.0, y(value), value);
+    }
+    let mut hour = 0.0;
+    while hour <= SIMULATION_LENGTH {
+        svg += &format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>\n", x(hour), height - 10.0, &format_clock_time(hour)[..5]);
+        hour += 120.0;
+    }
+    let points: Vec<String> = values.iter().enumerate().map(|(minute, value)| format!("{:.1},{:.1}", x(minute as f32), y(*value))).collect();
+    svg += &format!("<polyline points=\"{}\" fill=\"none\" stroke=\"#4a7ebb\" stroke-width=\"1.5\"/>\n", points.join(" "));
+    svg += &format!("<text x=\"{}\" y=\"12\">{}</text>\n</svg>\n", left, xml_escape(label));
+    return svg;
+}
+
+fn svg_bar_chart(labels : &[String], series : &[(&str, &str, Vec<f32>)]) -> String {
+    // Draws a horizontal bar per label for each series (name, colour, values), labels down the left side
+    let (bar, left, plot_width) = (12.0, 320.0, 500.0);
+    let group = bar * series.len() as f32 + 6.0;
+    let height = 30.0 + group * labels.len() as f32;
+    let largest = series.iter().flat_map(|(_, _, values)| values.iter().cloned()).fold(f32::MIN_POSITIVE, f32::max);
+
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">\n", left + plot_width + 80.0, height);
+    for (index, (name, colour, _)) in series.iter().enumerate() {
+        let legend_x = left + index as f32 * 180.0;
+        svg += &format!("<rect x=\"{}\" y=\"4\" width=\"10\" height=\"10\" fill=\"{}\"/>\n", legend_x, colour);
+        svg += &format!("<text x=\"{}\" y=\"13\">{}</text>\n", legend_x + 14.0, xml_escape(name));
+    }
+    for row in 0..labels.len() {
+        let top = 24.0 + group * row as f32;
+        svg += &format!("<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\" dominant-baseline=\"middle\">{}</text>\n",
+            left - 6.0, top + (group - 6.0) / 2.0, xml_escape(&labels[row]));
+        for (index, (_, colour, values)) in series.iter().enumerate() {
+            let y = top + bar * index as f32;
+            let length = values[row] * plot_width / largest;
+            svg += &format!("<rect x=\"{}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\"/>\n", left, y, length, bar - 1.0, colour);
+            svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" dominant-baseline=\"middle\">{:.2}</text>\n", left + length + 4.0, y + bar / 2.0, values[row]);
+        }
+    }
+    svg += "</svg>\n";
+    return svg;
+}
+
+fn read_timetable(path : &str, lines : &mut [Line]) -> Result<(), String> {
+    // Reads the departures from each terminal, one per line: <terminal station>,<HH:MM>[,<line name>]
+    // Trains leaving the first station of a line go east and trains leaving the last station go west
+    // Without a line name the departure is added to every line starting or ending at that station
+    // Blank lines and lines starting with # are ignored, departures outside the simulated day are dropped
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read timetable {}: {}", path, e))?;
+
+    for (line_number, text) in contents.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') { continue; }
+
+        let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 2 && fields.len() != 3 { return Err(format!("Timetable line {} is invalid", line_number + 1)); }
+        let departure = parse_clock_time(fields[1]).ok_or(format!("Timetable line {} has an invalid time: {}", line_number + 1, fields[1]))?;
+
+        if fields.len() == 3 && !lines.iter().any(|line| line.name.eq_ignore_ascii_case(fields[2])) {
+            return Err(format!("Timetable line {} does not name a line: {}", line_number + 1, fields[2]));
+        }
+        let mut found = false;
+        for line in lines.iter_mut() {
+            if fields.len() == 3 && !line.name.eq_ignore_ascii_case(fields[2]) { continue; }
+            let direction_index = match line.name_to_id(fields[0]) {
+                Some(0) => 0,
+                Some(id) if id == line.length() - 1 => 1,
+                _ => continue,
+            };
+            found = true;
+            if (0.0..SIMULATION_LENGTH).contains(&departure) {
+                line.timetable[direction_index].push(departure);
+            }
+        }
+        if !found {
+            return Err(format!("Timetable line {} does not name a terminal station{}: {}", line_number + 1,
+                if fields.len() == 3 {format!(" of {}", fields[2])} else {String::new()}, fields[0]));
+        }
+    }
+
+    for line in lines.iter_mut() {
+        for departures in line.timetable.iter_mut() {
+            departures.sort_by(|a, b| a.total_cmp(b));
+        }
+    }
+    return Ok(());
+}
+
+fn read_gradients(path : &str, lines : &mut [Line]) -> Result<(), String> {
+    // Reads the track grades between neighbouring stations, one segment per line:
+    // <station>,<next station>,<percent grade going from the first station to the second>
+    // Blank lines and lines starting with # are ignored
+    // A grade applies to every line running between the two stations, segments not listed are flat
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read gradient file {}: {}", path, e))?;
+
+    for (line_number, text) in contents.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') { continue; }
+
+        let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 3 { return Err(format!("Gradient file line {} is invalid", line_number + 1)); }
+        let grade : f32 = fields[2].parse().map_err(|_| format!("Gradient file line {} has an invalid grade", line_number + 1))?;
+
+        let mut found = false;
+        for line in lines.iter_mut() {
+            if let (Some(a), Some(b)) = (line.name_to_id(fields[0]), line.name_to_id(fields[1])) {
+                if a + 1 == b { line.inter_station_gradients[a] = grade; found = true; }
+                if b + 1 == a { line.inter_station_gradients[b] = -grade; found = true; }
+            }
+        }
+        if !found {
+            return Err(format!("Gradient file line {} does not name two neighbouring stations: {} / {}", line_number + 1, fields[0], fields[1]));
+        }
+    }
+    return Ok(());
+}
+
+type Turnback = (usize, usize); // LINE ID, STATION ID of a turnback track
+
+fn read_services(path : &str, lines : &[Line]) -> Result<(Vec<Turnback>, Vec<ServicePattern>), String> {
+    // Reads the turnback tracks and short turn patterns, one per line:
+    // turnback,<station>
+    // short,<first station>,<last station>,<from minute>,<to minute>,<every n-th train>
+    // Blank lines and lines starting with # are ignored
+    // Each entry applies to every line serving the stations named
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read service file {}: {}", path, e))?;
+    let mut turnbacks = Vec::new();
+    let mut patterns = Vec::new();
+
+    for (line_number, text) in contents.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') { continue; }
+
+        let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+        let bad_line = || format!("Service file line {} is invalid", line_number + 1);
+        let unknown_station = || format!("Service file line {} has an unknown station: {}", line_number + 1, fields[1..fields.len().min(3)].join(" / "));
+        let count_before = turnbacks.len() + patterns.len();
+
+        match (fields[0].to_lowercase().as_str(), fields.len()) {
+            ("turnback", 2) => {
+                for line_id in 0..lines.len() {
+                    if let Some(station_id) = lines[line_id].name_to_id(fields[1]) {
+                        turnbacks.push((line_id, station_id));
+                    }
+                }
+            },
+            ("short", 6) => {
+                let from : f32 = fields[3].parse().map_err(|_| bad_line())?;
+                let to : f32 = fields[4].parse().map_err(|_| bad_line())?;
+                let every : u32 = fields[5].parse().map_err(|_| bad_line())?;
+                for line_id in 0..lines.len() {
+                    if let (Some(a), Some(b)) = (lines[line_id].name_to_id(fields[1]), lines[line_id].name_to_id(fields[2])) {
+                        if a == b || every == 0 { return Err(bad_line()); }
+                        patterns.push(ServicePattern {line : line_id, first_station : a.min(b), last_station : a.max(b), from : from, to : to, every : every, releases : 0});
+                    }
+                }
+            },
+            _ => return Err(bad_line()),
+        }
+        if turnbacks.len() + patterns.len() == count_before { return Err(unknown_station()); }
+    }
+
+    // Trains start from a terminal and reverse at the other end of their pattern
+    for pattern in &patterns {
+        let line = &lines[pattern.line];
+        let last_station = line.length() - 1;
+        let turnback = if pattern.first_station == 0 {pattern.last_station} else {pattern.first_station};
+        if pattern.first_station != 0 && pattern.last_station != last_station {
+            return Err(format!("Short turn {} to {} does not start at a terminal", line.id_to_name(pattern.first_station), line.id_to_name(pattern.last_station)));
+        }
+        if turnback != 0 && turnback != last_station && !turnbacks.contains(&(pattern.line, turnback)) {
+            return Err(format!("{} has no turnback track", line.id_to_name(turnback)));
+        }
+    }
+    return Ok((turnbacks, patterns));
+}
+
+fn csv_field(text : &str) -> String {
+    // Quotes a field for a CSV file we write if it holds a comma, quote or line break (the reverse of split_csv_row)
+    if text.contains([',', '"', '\n']) {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    return String::from(text);
+}
+
+fn split_csv_row(row : &str) -> Vec<String> {
+    // Splits one row of a CSV file, fields in double quotes can hold commas and "" for a quote
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => { field.push('"'); chars.next(); },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    return fields.iter().map(|f| String::from(f.trim())).collect();
+}
+
+struct GtfsTable { // One file of a GTFS feed
+    name: String,
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl GtfsTable {
+
+    fn read(directory : &str, name : &str) -> Result<GtfsTable, String> {
+        let path = std::path::Path::new(directory).join(name);
+        let contents = std::fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let mut rows = contents.trim_start_matches('\u{feff}').lines().filter(|row| !row.trim().is_empty()).map(split_csv_row);
+        let header = rows.next().ok_or(format!("{} is empty", name))?;
+        return Ok(GtfsTable {name: String::from(name), header: header, rows: rows.collect()});
+    }
+
+    fn column(&self, column : &str) -> Result<usize, String> {
+        return self.header.iter().position(|name| name == column).ok_or(format!("{} has no {} column", self.name, column));
+    }
+
+    fn optional_column(&self, column : &str) -> Option<usize> {
+        return self.header.iter().position(|name| name == column);
+    }
+
+    fn field<'a>(&self, row : &'a [String], column : usize) -> Result<&'a String, String> {
+        // A row's value in the given column, rows cut short are an error instead of a panic
+        return row.get(column).ok_or(format!("{} has a row with no {} field", self.name, self.header[column]));
+    }
+}
+
+fn service_ids_for(directory : &str, service : &str) -> Result<Vec<String>, String> {
+    // A service day is either a service_id, or a day of the week looked up in calendar.txt
+    let weekdays = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+    let day = service.to_lowercase();
+    if !weekdays.contains(&day.as_str()) {
+        return Ok(vec![String::from(service)]);
+    }
+    let calendar = GtfsTable::read(directory, "calendar.txt")?;
+    let (service_column, day_column) = (calendar.column("service_id")?, calendar.column(&day)?);
+    return calendar.rows.iter().filter(|row| row.get(day_column).is_some_and(|runs| runs == "1"))
+        .map(|row| calendar.field(row, service_column).cloned()).collect();
+}
+
+fn gtfs_time(text : &str) -> Result<f32, String> {
+    return parse_clock_time(text).ok_or(format!("stop_times.txt has an invalid time: {}", text));
+}
+
+fn read_gtfs_line(directory : &str, route : &str, service : Option<&str>) -> Result<Line, String> {
+    // Builds a line out of one route of a GTFS feed (stops.txt, routes.txt, trips.txt and stop_times.txt)
+    // The stations are those of the route's longest stopping pattern, which becomes the eastward direction
+    // Travel times are the median over every trip running the whole pattern, distances are straight lines between stops
+    // Departures of those trips from either end are loaded as the line's timetable
+    let routes = GtfsTable::read(directory, "routes.txt")?;
+    let route_id_column = routes.column("route_id")?;
+    let short_name_column = routes.optional_column("route_short_name");
+    let long_name_column = routes.optional_column("route_long_name");
+    let named = |row : &Vec<String>, column : Option<usize>| column.and_then(|c| row.get(c)).is_some_and(|name| name == route);
+    let route_row = routes.rows.iter().find(|row| named(row, Some(route_id_column)) || named(row, short_name_column) || named(row, long_name_column))
+        .ok_or(format!("routes.txt has no route {}", route))?;
+    let route_id = routes.field(route_row, route_id_column)?.clone();
+    let line_name = long_name_column.or(short_name_column).and_then(|c| route_row.get(c)).filter(|name| !name.is_empty())
+        .cloned().unwrap_or(route_id.clone());
+
+    // Trips of the route on the service day, without one we take the service with the most trips
+    let trips = GtfsTable::read(directory, "trips.txt")?;
+    let (trip_route_column, trip_id_column, trip_service_column) = (trips.column("route_id")?, trips.column("trip_id")?, trips.column("service_id")?);
+    let route_trips: Vec<&Vec<String>> = trips.rows.iter().filter(|row| row.get(trip_route_column) == Some(&route_id)).collect();
+    let services = match service {
+        Some(service) => service_ids_for(directory, service)?,
+        None => {
+            let mut counts: HashMap<&String, usize> = HashMap::new();
+            for row in &route_trips { *counts.entry(trips.field(row, trip_service_column)?).or_insert(0) += 1; }
+            let busiest = counts.into_iter().max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0))).map(|(id, _)| id.clone());
+            busiest.into_iter().collect()
+        },
+    };
+    let direction_column = trips.optional_column("direction_id");
+    let service_trips: Vec<&&Vec<String>> = route_trips.iter().filter(|row| row.get(trip_service_column).is_some_and(|id| services.contains(id))).collect();
+    let trip_ids: Vec<String> = service_trips.iter().map(|row| trips.field(row, trip_id_column).cloned()).collect::<Result<_, _>>()?;
+    let outbound: Vec<bool> = service_trips.iter().map(|row| direction_column.and_then(|c| row.get(c)).is_none_or(|direction| direction != "1")).collect();
+    if trip_ids.is_empty() {
+        return Err(format!("Route {} has no trips on service day {}", route, service.unwrap_or("(any)")));
+    }
+    let trip_index: HashMap<&String, usize> = trip_ids.iter().enumerate().map(|(index, id)| (id, index)).collect();
+
+    // Every trip's stops in order: STOP SEQUENCE, STOP ID, ARRIVAL, DEPARTURE
+    let stop_times = GtfsTable::read(directory, "stop_times.txt")?;
+    let (st_trip, st_stop, st_sequence) = (stop_times.column("trip_id")?, stop_times.column("stop_id")?, stop_times.column("stop_sequence")?);
+    let (st_arrival, st_departure) = (stop_times.column("arrival_time")?, stop_times.column("departure_time")?);
+    let mut trip_stops: Vec<Vec<(u32, String, f32, f32)>> = vec![Vec::new(); trip_ids.len()];
+    for row in &stop_times.rows {
+        let index = match trip_index.get(stop_times.field(row, st_trip)?) {
+            Some(index) => *index,
+            None => continue,
+        };
+        let (arrival, departure) = (stop_times.field(row, st_arrival)?, stop_times.field(row, st_departure)?);
+        if arrival.is_empty() || departure.is_empty() { continue; } // Untimed stop, interpolated by the agency's tools
+        let sequence_text = stop_times.field(row, st_sequence)?;
+        let sequence : u32 = sequence_text.parse().map_err(|_| format!("stop_times.txt has an invalid stop_sequence: {}", sequence_text))?;
+        trip_stops[index].push((sequence, stop_times.field(row, st_stop)?.clone(), gtfs_time(arrival)?, gtfs_time(departure)?));
+    }
+    for stops in trip_stops.iter_mut() {
+        stops.sort_by_key(|stop| stop.0);
+    }
+
+    // The longest pattern (ties go to the most common, then to direction 0 trips) gives the stations
+    let patterns: Vec<Vec<&String>> = trip_stops.iter().map(|stops| stops.iter().map(|stop| &stop.1).collect()).collect();
+    let pattern_count = |pattern : &Vec<&String>| patterns.iter().filter(|other| *other == pattern).count();
+    let longest = (0..patterns.len()).max_by(|&a, &b| patterns[a].len().cmp(&patterns[b].len())
+        .then(pattern_count(&patterns[a]).cmp(&pattern_count(&patterns[b]))).then(outbound[a].cmp(&outbound[b])).then(b.cmp(&a)))
+        .filter(|&trip| patterns[trip].len() >= 2).ok_or(format!("Route {} has no trips with at least two timed stops", route))?;
+    let pattern = patterns[longest].clone();
+    let reversed: Vec<&String> = pattern.iter().rev().copied().collect();
+
+    // Station names and positions
+    let stops = GtfsTable::read(directory, "stops.txt")?;
+    let (stop_id_column, stop_name_column) = (stops.column("stop_id")?, stops.column("stop_name")?);
+    let (lat_column, lon_column) = (stops.column("stop_lat")?, stops.column("stop_lon")?);
+    let mut names = Vec::new();
+    let mut positions = Vec::new();
+    let mut coordinates = Vec::new();
+    for stop_id in &pattern {
+        let row = stops.rows.iter().find(|row| row.get(stop_id_column) == Some(*stop_id)).ok_or(format!("stops.txt has no stop {}", stop_id))?;
+        let lat : f32 = stops.field(row, lat_column)?.parse().map_err(|_| format!("stops.txt has an invalid stop_lat for {}", stop_id))?;
+        let lon : f32 = stops.field(row, lon_column)?.parse().map_err(|_| format!("stops.txt has an invalid stop_lon for {}", stop_id))?;
+        names.push(stops.field(row, stop_name_column)?.clone());
+        positions.push((lat.to_radians(), lon.to_radians()));
+        coordinates.push((lat, lon));
+    }
+
+    // Run times between stops from every trip over the whole pattern, plus its departure from the terminal
+    let segments = pattern.len() - 1;
+    let mut run_times: Vec<Vec<f32>> = vec![Vec::new(); segments];
+    let mut timetable = [Vec::new(), Vec::new()];
+    for trip in 0..trip_stops.len() {
+        let direction_index = if patterns[trip] == pattern {0} else if patterns[trip] == reversed {1} else {continue};
+        let stops = &trip_stops[trip];
+        for i in 0..segments {
+            let segment = if direction_index == 0 {i} else {segments - 1 - i};
+            run_times[segment].push(stops[i + 1].2 - stops[i].3);
+        }
+        let departure = stops[0].3;
+        if (0.0..SIMULATION_LENGTH).contains(&departure) { timetable[direction_index].push(departure); }
+    }
+    let mut traveltimes = Vec::new();
+    let mut distances = Vec::new();
+    for segment in 0..segments {
+        let times = &mut run_times[segment];
+        times.sort_by(|a, b| a.total_cmp(b));
+        traveltimes.push(times[times.len() / 2].max(TRAIN_STOP_TIME));
+
+        // Haversine distance
+        let ((lat_a, lon_a), (lat_b, lon_b)) = (positions[segment], positions[segment + 1]);
+        let h = ((lat_b - lat_a) / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * ((lon_b - lon_a) / 2.0).sin().powi(2);
+        distances.push(2.0 * EARTH_RADIUS * h.sqrt().asin());
+    }
+
+    // GTFS has no ridership, use our own numbers for the SkyTrain stations we know
+    let known_lines = skytrain_lines();
+    let iats: Vec<f32> = names.iter().map(|name| {
+        known_lines.iter().find_map(|line| line.name_to_id(name).map(|id| line.stations[id].customer_iat)).unwrap_or(DEFAULT_STATION_IAT)
+    }).collect();
+
+    let name_refs: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    let mut line = Line::new(line_name, &name_refs, traveltimes, distances, iats);
+    for departures in timetable.iter_mut() {
+        departures.sort_by(|a, b| a.total_cmp(b));
+    }
+    line.timetable = timetable;
+    line.positions = coordinates;
+    return Ok(line);
+}
+
+fn millennium_line() -> Line {
     // Create Millenium Line
     let m_line_station_names = ["VCC-Clark", "Commercial–Broadway", "Renfrew", "Rupert", "Gilmore", "Brentwood Town Centre",
                                             "Holdom", "Sperling–Burnaby Lake", "Lake City Way", "Production Way–University", "Lougheed Town Centre",
                                             "Burquitlam", "Moody Centre", "Inlet Centre", "Coquitlam Central", "Lincoln", "Lafarge Lake - Douglas"];
     let m_line_station_traveltimes: Vec<f32> = vec![1.0, 3.0, 1.0, 2.0, 2.0, 2.0, 2.0, 3.0, 2.0, 2.0, 3.0, 5.0, 2.0, 3.0, 2.0, 1.0];
+    let m_line_station_distances: Vec<f32> = vec![1.3, 1.9, 1.0, 1.6, 1.5, 1.3, 1.6, 1.8, 1.2, 1.7, 1.6, 3.6, 1.1, 1.4, 1.1, 0.9]; // Approximate, in km
     let m_line_station_iats = vec![2.325, 15.909, 2.842, 2.05, 2.850, 5.483, 2.225, 1.55, 0.825, 4.125, 9.625, 3.817, 1.933, 1.650, 4.033, 2.925, 1.883];
 
-    let millennium_line: Line = Line::new(String::from("Millennium Line"), &m_line_station_names, m_line_station_traveltimes, m_line_station_iats);
+    let mut line = Line::new(String::from("Millennium Line"), &m_line_station_names, m_line_station_traveltimes, m_line_station_distances, m_line_station_iats);
 
-    // Load Trains
-    let mut train_list: Vec<Train> = Vec::new();
-    for i in 0..NUMBER_OF_TRAINS {
-        train_list.push(Train::new(i));
+    // Stations every express or skip-stop train still stops at (terminals, transfers and the busiest stations)
+    let m_line_major_stations = ["VCC-Clark", "Commercial–Broadway", "Brentwood Town Centre", "Production Way–University", "Lougheed Town Centre",
+                                            "Burquitlam", "Moody Centre", "Coquitlam Central", "Lafarge Lake - Douglas"];
+    line.set_major_stations(&m_line_major_stations);
+
+    return line;
+}
+
+fn skytrain_lines() -> Vec<Line> {
+    // Create the Millennium Line plus both Expo Line branches, which share the track from Waterfront to Columbia
+    // Expo trains meet the Millennium Line at Commercial–Broadway, and the Production Way branch runs alongside it from Lougheed
+    let expo_trunk_names = ["Waterfront", "Burrard", "Granville", "Stadium–Chinatown", "Main Street–Science World", "Commercial–Broadway",
+                                            "Nanaimo", "29th Avenue", "Joyce–Collingwood", "Patterson", "Metrotown", "Royal Oak", "Edmonds",
+                                            "22nd Street", "New Westminster", "Columbia"];
+    let expo_trunk_traveltimes: Vec<f32> = vec![1.0, 1.0, 2.0, 2.0, 3.0, 2.0, 1.0, 2.0, 2.0, 1.0, 2.0, 2.0, 3.0, 2.0, 1.0];
+    let expo_trunk_distances: Vec<f32> = vec![0.6, 0.5, 0.9, 1.4, 2.4, 1.3, 0.9, 1.3, 1.2, 0.9, 1.6, 1.6, 2.2, 1.3, 0.5]; // Approximate, in km
+    let expo_trunk_iats = vec![20.0, 12.0, 12.0, 5.0, 7.0, 15.909, 2.5, 2.5, 8.0, 3.0, 12.0, 3.0, 3.5, 2.0, 6.0, 3.0];
+
+    // Each branch continues on from Columbia
+    let king_george_names = ["Scott Road", "Gateway", "Surrey Central", "King George"];
+    let king_george_traveltimes: Vec<f32> = vec![3.0, 2.0, 2.0, 1.0];
+    let king_george_distances: Vec<f32> = vec![2.9, 1.6, 0.9, 0.8];
+    let king_george_iats = vec![4.0, 3.0, 9.0, 5.0];
+    let production_way_names = ["Sapperton", "Braid", "Lougheed Town Centre", "Production Way–University"];
+    let production_way_traveltimes: Vec<f32> = vec![2.0, 2.0, 4.0, 2.0];
+    let production_way_distances: Vec<f32> = vec![1.4, 1.3, 3.0, 1.7];
+    let production_way_iats = vec![2.5, 2.0, 9.625, 4.125];
+
+    let expo_major_stations = ["Waterfront", "Burrard", "Commercial–Broadway", "Joyce–Collingwood", "Metrotown", "New Westminster", "Columbia",
+                                            "Surrey Central", "King George", "Lougheed Town Centre", "Production Way–University"];
+
+    let mut lines = vec![millennium_line()];
+    let branches = [("Expo Line (King George)", &king_george_names, king_george_traveltimes, king_george_distances, king_george_iats),
+                    ("Expo Line (Production Way)", &production_way_names, production_way_traveltimes, production_way_distances, production_way_iats)];
+    for (name, branch_names, branch_traveltimes, branch_distances, branch_iats) in branches {
+        let mut names = expo_trunk_names.to_vec();
+        names.extend_from_slice(branch_names);
+        let traveltimes = [expo_trunk_traveltimes.clone(), branch_traveltimes].concat();
+        let distances = [expo_trunk_distances.clone(), branch_distances].concat();
+        let iats = [expo_trunk_iats.clone(), branch_iats].concat();
+
+        let mut line = Line::new(String::from(name), &names, traveltimes, distances, iats);
+        line.set_major_stations(&expo_major_stations);
+        lines.push(line);
     }
 
+    return lines;
+}
+
+fn build_simulation(options : &SimOptions, seed : u64) -> Simulation {
+    // Sets up a simulation and its initial events, ready to be run
+
+    // Initalize
+    let mut lines = options.lines.clone();
+
+    // Load Trains
+    let train_list: Vec<Train> = options.fleet.build_trains();
+
     // FEL
     let future_event_list : BinaryHeap<DiscreteEvent> = BinaryHeap::new();
-    let mut new_event : DiscreteEvent;
     
     // RNG streams (For CRN)
-    let customer_arrival_rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    // Every station draws its arrivals from its own stream, so an antithetic day mirrors each station's arrivals
+    // NOTE: The stations are only known once the lines are linked, the arrival streams are made below
+    let mut destination_rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    destination_rng.set_stream(7);
+    let mut failure_rng = None;
+    if options.failures_enabled {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(1); // Own stream so failures don't change customer arrivals
+        failure_rng = Some(rng);
+    }
+    let mut disruption_rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    disruption_rng.set_stream(2);
+    let mut route_rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    route_rng.set_stream(3);
+    let mut patience_rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    patience_rng.set_stream(4);
+    let mut platform_rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    platform_rng.set_stream(5);
 
     // Distribution for TransLinks dispact times by the hour
     // vec![0.1, 0.3, 0.7, 0.8, 1.1, 1.5, 1.1, 0.8, 0.7, 0.85, 1.2, 1.35, 1.6, 1.35, 1.1, 0.9, 0.7, 0.5, 0.3, 0.1, 0.0];
@@ -707,32 +3985,145 @@ fn main() {
     let time_ait_periods = 
        vec![10.0, 9.0, 8.0, 7.0, 6.0, 5.0, 6.0, 7.0, 7.0, 6.0, 5.0, 4.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0];
 
+    // Platform limits
+    if let Some(capacity) = options.platform_capacity {
+        for line in lines.iter_mut() {
+            for station in line.stations.iter_mut() {
+                station.platform_capacity = capacity;
+            }
+        }
+    }
+
+    // Short turns
+    for (line_id, station_id) in &options.turnbacks {
+        lines[*line_id].turnbacks[*station_id] = true;
+    }
+    for pattern in &options.service_patterns {
+        lines[pattern.line].service_patterns.push(pattern.clone());
+    }
+    for line in lines.iter_mut() {
+        line.set_stopping_patterns(&options.stopping_policy);
+    }
+
+    // Planned disruptions
+    let mut closure_starts = Vec::new();
+    for disruption in &options.disruptions {
+        match disruption {
+            Disruption::StationClosure(line_id, station_id, from, to) => {
+                lines[*line_id].closures.push((*station_id, *from, *to));
+                closure_starts.push((*line_id, *station_id, *from));
+            },
+            Disruption::SingleTrack(line_id, first_station, last_station, from, to) => {
+                lines[*line_id].single_track.push(SingleTrackSection {first_station : *first_station, last_station : *last_station, from : *from, to : *to,
+                    direction : EASTWARD, trains_inside : Vec::new(), waiting : VecDeque::new()});
+            },
+        }
+    }
+
+    // Connect the lines up and work out how customers get between any two stations
+    // Customers expect to wait half of the average time between trains on the line they are boarding,
+    // and count each minute riding it by how crowded its trains should be
+    // NOTE: A train's expected load is what arrives at the line's stations in half a headway, riding a third of the line
+    let (seats, standing_area) = options.fleet.average_seating();
+    let mut expected_waits = Vec::new();
+    let mut crowding_factors = Vec::new();
+    for line in &lines {
+        let average_headway = match options.dispatch_type {
+            DispatchTypes::Constant(lambda) => lambda,
+            DispatchTypes::TransLink() => tl_ait_periods.iter().sum::<f32>() / tl_ait_periods.len() as f32,
+            DispatchTypes::TimeBased(_) => time_ait_periods.iter().sum::<f32>() / time_ait_periods.len() as f32,
+            DispatchTypes::PopBased(multi) => line.expected_population_headway(multi, options.fleet.average_capacity()),
+            DispatchTypes::Timetable() => {
+                // Average gap between this line's departures in either direction
+                let mut span = 0.0;
+                let mut gaps = 0.0;
+                for departures in &line.timetable {
+                    if departures.len() < 2 { continue; }
+                    span += departures[departures.len() - 1] - departures[0];
+                    gaps += (departures.len() - 1) as f32;
+                }
+                if gaps > 0.0 {span / gaps} else {10.0}
+            },
+        };
+        expected_waits.push(average_headway / 2.0);
+        let expected_load = line.average_arrival_rate() * average_headway / 2.0 / 3.0;
+        crowding_factors.push(crowding_multiplier(expected_load, seats, standing_area));
+    }
+    let mut network = Network::link(&mut lines, options.route_choice.clone());
+    network.plan_trips(&lines, &expected_waits, &crowding_factors);
+    let mut arrival_rngs = Vec::new();
+    for network_station in 0..network.length() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(ARRIVAL_STREAM_BASE + network_station as u64);
+        arrival_rngs.push(rng);
+    }
+
     // Create simulator object
-    let mut sim : Simulation = Simulation {line : millennium_line, train_list : train_list, future_event_list : future_event_list, 
-        time_elapsed : 0.0, customer_iat : customer_arrival_rng, bookkeeping : Bookkeeper::new(), dispatch_type: dispatch_type, 
+    let mut sim : Simulation = Simulation {lines : lines, network : network, fleet : options.fleet.clone(), train_list : train_list, 
+        consist_preference : options.consist_preference.clone(), coupling_rule : options.coupling_rule.clone(), 
+        stopping_policy : options.stopping_policy.clone(),
+        future_event_list : future_event_list, events_added : 0, time_elapsed : 0.0, arrival_rngs : arrival_rngs, 
+        destination_rng : destination_rng, failure_rng : failure_rng, disruption_rng : disruption_rng, route_rng : route_rng, patience_rng : patience_rng, platform_rng : platform_rng,
+        balk_queue_length : options.balk_queue_length, mean_patience : options.mean_patience,
+        platform_capacity : options.platform_capacity, meter_rate : options.meter_rate,
+        arrival_draws : options.arrival_draws.clone(), log_movements : options.log_movements, movements : Vec::new(), trace : None, log : Logger::off(), bookkeeping : Bookkeeper::new(), dispatch_type: options.dispatch_type.clone(), 
         translink_sampler : tl_ait_periods, timebased_sampler: time_ait_periods};
 
+    sim.bookkeeping.track_od_pairs(sim.network.length());
+
     // Add inital events
     // Train releases
-    sim.add_event(EventTypes::TrainRelease(EASTWARD), 0.0);
-    sim.add_event(EventTypes::TrainRelease(WESTWARD), 0.0);
+    for line_id in 0..sim.lines.len() {
+        if matches!(sim.dispatch_type, DispatchTypes::Timetable()) {
+            for (direction_index, direction) in [EASTWARD, WESTWARD].into_iter().enumerate() {
+                if let Some(first) = sim.lines[line_id].timetable[direction_index].first().copied() {
+                    sim.add_event(EventTypes::ScheduledRelease(line_id, direction, 0), first);
+                }
+            }
+        } else {
+            sim.add_event(EventTypes::TrainRelease(line_id, EASTWARD), 0.0);
+            sim.add_event(EventTypes::TrainRelease(line_id, WESTWARD), 0.0);
+        }
+    }
     sim.add_event(EventTypes::PollStats(), 0.0);
 
     // CUstomer arrivals
-    for i in 0..sim.line.length() {
+    for i in 0..sim.network.length() {
         sim.add_event(EventTypes::CustomerArrival(i), FIRST_CUSTOMER_ARRIVALS_AT);
     }
+
+    for (line_id, station_id, from) in closure_starts {
+        sim.add_event(EventTypes::StationClosure(line_id, station_id), from);
+    }
     
-    // Add trains to queues equally
-    let mut dir: i8 = 1;
+    // Split the trains between the lines by how long a trip on each takes, then add them to each line's queues equally
+    let mut line_share = Vec::new();
+    let mut total_traveltime = 0.0;
+    for line in &sim.lines {
+        line_share.push(line.total_traveltime());
+        total_traveltime += line.total_traveltime();
+    }
+    let mut line_trains = vec![0.0; sim.lines.len()];
     for i in 0..sim.train_list.len() {
-        if dir == EASTWARD {sim.line.east_trains.push_back(i);}
-        else {sim.line.west_trains.push_back(i);}
-        dir *= -1;
+        let mut line_id = 0;
+        for other in 1..sim.lines.len() {
+            if line_trains[other] * total_traveltime / line_share[other] < line_trains[line_id] * total_traveltime / line_share[line_id] {
+                line_id = other;
+            }
+        }
+        sim.train_list[i].line = line_id;
+        if line_trains[line_id] % 2.0 == 0.0 {sim.lines[line_id].east_trains.push_back(i);}
+        else {sim.lines[line_id].west_trains.push_back(i);}
+        line_trains[line_id] += 1.0;
     }
 
-    // START SIMULATION TIMER
-    let timer = time::Instant::now();
+    return sim;
+}
+
+fn run_simulation(mut sim : Simulation) -> Simulation {
+    // Runs the event loop until the end of the day and fills in the train stats
+
+    let mut new_event : DiscreteEvent;
 
     // Event Loop
     while sim.time_elapsed < SIMULATION_LENGTH && !sim.future_event_list.is_empty()  {
@@ -749,37 +4140,850 @@ fn main() {
             EventTypes::PollStats() => sim = poll_stats(sim),
             EventTypes::TrainArrival(train_id, station_id) => sim = train_arrival(sim, train_id, station_id),
             EventTypes::TrainDeparture(train_id, station_id) => sim = train_departure(sim, train_id, station_id),
-            EventTypes::TrainRelease(dir) => sim = release_train(sim, dir),
+            EventTypes::TrainRelease(line_id, dir) => sim = release_train(sim, line_id, dir),
+            EventTypes::ScheduledRelease(line_id, dir, departure) => sim = scheduled_release(sim, line_id, dir, departure),
+            EventTypes::TrainFailure(train_id, trip) => sim = train_failure(sim, train_id, trip),
+            EventTypes::TrainRescue(train_id) => sim = train_rescue(sim, train_id),
+            EventTypes::StationClosure(line_id, station_id) => sim = station_closure(sim, line_id, station_id),
             EventTypes::CustomerArrival(station_id) => sim = customer_arrival(sim, station_id)
         }
+        if sim.trace.is_some() { sim.trace_event(&new_event); }
     }
 
-    // Stop timer
-    let sim_realtime = timer.elapsed();
-    println!("-- Time to execute: {:.2?} --", sim_realtime);
-
-    // Generate and print report
-
     // Sim needs to do some extra work for train related stats
     let mut usage_perecnt : f32;
     let mut max_usage_percent : f32 = 0.0;
     let mut time_full_percent : f32;
-    for i in 0..usize::from(NUMBER_OF_TRAINS) {
-        usage_perecnt =  (sim.train_list[i].percent_full_total * 100.0) / sim.train_list[i].percent_full_test_amount;
-        time_full_percent = (sim.train_list[i].times_full * 100.0) / sim.train_list[i].percent_full_test_amount;
-        if PRINT_TRAIN_INFO {println!("Train {}: Usage percent {}% | Full percent {}%", i, usage_perecnt, time_full_percent);}
+    let type_count = sim.fleet.types.len();
+    sim.bookkeeping.type_names = sim.fleet.types.iter().map(|t| t.name.clone()).collect();
+    sim.bookkeeping.type_trains = vec![0.0; type_count];
+    sim.bookkeeping.type_util_percent = vec![0.0; type_count];
+    sim.bookkeeping.type_full_percent = vec![0.0; type_count];
+    sim.bookkeeping.type_energy = vec![0.0; type_count];
+    for i in 0..sim.train_list.len() {
+        if sim.train_list[i].percent_full_test_amount > 0.0 {
+            usage_perecnt =  (sim.train_list[i].percent_full_total * 100.0) / sim.train_list[i].percent_full_test_amount;
+            time_full_percent = (sim.train_list[i].times_full * 100.0) / sim.train_list[i].percent_full_test_amount;
+        } else { // Never left the terminal
+            usage_perecnt = 0.0;
+            time_full_percent = 0.0;
+        }
+        sim.log.write(LogCategory::Train(), LogLevel::Info(), SIMULATION_LENGTH, Some(i), None,
+            || format!("Train {}: Usage percent {}% | Full percent {}%", i, usage_perecnt, time_full_percent));
         sim.bookkeeping.total_trains += 1.0;
+        sim.bookkeeping.train_util_percent.push(usage_perecnt);
         sim.bookkeeping.average_train_util_percent += usage_perecnt;
         sim.bookkeeping.time_train_full_percent += time_full_percent;
         if max_usage_percent < (sim.train_list[i].percent_full_max * 100.0) {
             max_usage_percent = sim.train_list[i].percent_full_max * 100.0;
         }
+
+        let type_id = sim.train_list[i].type_id;
+        sim.bookkeeping.type_trains[type_id] += 1.0;
+        sim.bookkeeping.type_util_percent[type_id] += usage_perecnt;
+        sim.bookkeeping.type_full_percent[type_id] += time_full_percent;
+        sim.bookkeeping.type_energy[type_id] += sim.train_list[i].energy_used;
+        sim.bookkeeping.max_train_energy = sim.bookkeeping.max_train_energy.max(sim.train_list[i].energy_used);
     }
 
     sim.bookkeeping.max_train_util_percent = max_usage_percent;
     sim.bookkeeping.average_train_util_percent /= sim.bookkeeping.total_trains;
     sim.bookkeeping.time_train_full_percent /= sim.bookkeeping.total_trains;
+    for type_id in 0..type_count {
+        if sim.bookkeeping.type_trains[type_id] == 0.0 { continue; }
+        sim.bookkeeping.type_util_percent[type_id] /= sim.bookkeeping.type_trains[type_id];
+        sim.bookkeeping.type_full_percent[type_id] /= sim.bookkeeping.type_trains[type_id];
+        sim.bookkeeping.type_energy[type_id] /= sim.bookkeeping.type_trains[type_id];
+    }
+
+    // Empty FEL
+    sim.future_event_list.clear();
+
+    return sim;
+}
+
+fn run_replications(options : &SimOptions, first_seed : u64, replications : usize) -> Vec<Bookkeeper> {
+    // Runs the same day with seeds first_seed, first_seed + 1, ... so every configuration sees the same customers (CRN)
+    let mut results = Vec::new();
+    for replication in 0..replications {
+        results.push(run_simulation(build_simulation(options, first_seed + replication as u64)).bookkeeping);
+    }
+    return results;
+}
+
+fn mean_and_half_width(samples : &[f32]) -> (f32, f32) {
+    // Sample mean and the half width of its 95% confidence interval
+    let n = samples.len() as f32;
+    let mean = samples.iter().sum::<f32>() / n;
+    if samples.len() < 2 { return (mean, f32::INFINITY); }
+    let variance = samples.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / (n - 1.0);
+    let t = T_QUANTILES.get(samples.len() - 2).copied().unwrap_or(1.96);
+    return (mean, t * (variance / n).sqrt());
+}
+
+struct SearchSettings { // How to search for the best dispatch parameter
+    method: SearchMethod,
+    range: (f32, f32), // Lowest and highest parameter to try
+    replications: usize,
+    objective: Objective,
+    costs: CostModel,
+    max_trains: Option<f32>, // Most trains that can be in service at once
+}
+
+struct Evaluation { // How one dispatch parameter did over every replication
+    parameter: f32,
+    mean: f32,
+    half_width: f32,
+    peak_trains: f32, // Most trains in service at once, averaged over the replications
+    feasible: bool,
+}
+
+fn evaluate_parameter(options : &SimOptions, parameter : f32, first_seed : u64, settings : &SearchSettings) -> Evaluation {
+    let mut candidate = options.clone();
+    candidate.dispatch_type = options.dispatch_type.with_parameter(parameter).expect("ERR: DISPATCH HAS NO PARAMETER");
+    let results = run_replications(&candidate, first_seed, settings.replications);
+    let values: Vec<f32> = results.iter().map(|bookkeeping| settings.objective.value(bookkeeping, &settings.costs)).collect();
+    let (mean, half_width) = mean_and_half_width(&values);
+    let peak_trains = results.iter().map(|bookkeeping| bookkeeping.max_trains_deployed).sum::<f32>() / settings.replications as f32;
+    let evaluation = Evaluation {parameter: parameter, mean: mean, half_width: half_width, peak_trains: peak_trains,
+        feasible: settings.max_trains.is_none_or(|limit| peak_trains <= limit)};
+    print!("    {} = {:.3}: {:.2} +/- {:.2}, PEAK TRAINS {:.1}{}\n", options.dispatch_type.parameter_name(), parameter, evaluation.mean,
+        evaluation.half_width, evaluation.peak_trains, if evaluation.feasible {""} else {" (OVER FLEET LIMIT)"});
+    return evaluation;
+}
+
+fn optimize_dispatch(options : &SimOptions, first_seed : u64, settings : &SearchSettings) -> Option<Evaluation> {
+    // Searches the dispatch parameter for the one with the lowest objective, returns None if nothing met the fleet limit
+    let (low, high) = settings.range;
+    let mut evaluations = Vec::new();
+    match settings.method {
+        SearchMethod::Grid(points) => {
+            for point in 0..points {
+                let parameter = if points == 1 {low} else {low + (high - low) * point as f32 / (points - 1) as f32};
+                evaluations.push(evaluate_parameter(options, parameter, first_seed, settings));
+            }
+        },
+        SearchMethod::Anneal(steps) => {
+            // Own stream so the search doesn't touch the simulation's random numbers
+            let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(first_seed);
+            rng.set_stream(6);
+            let step = Normal::new(0.0, ANNEAL_STEP * (high - low)).unwrap();
+
+            let mut current = evaluate_parameter(options, (low + high) / 2.0, first_seed, settings);
+            let mut temperature = ANNEAL_START_TEMPERATURE * current.mean.abs().max(1.0);
+            for _ in 0..steps {
+                let parameter = clamp(current.parameter + step.sample(&mut rng), low, high);
+                let neighbour = evaluate_parameter(options, parameter, first_seed, settings);
+                let accept_chance = if !neighbour.feasible && current.feasible {0.0} else {(-(neighbour.mean - current.mean) / temperature).exp()};
+                let moved = rand::Rng::gen::<f32>(&mut rng) < accept_chance;
+                temperature *= ANNEAL_COOLING;
+                if moved {
+                    evaluations.push(std::mem::replace(&mut current, neighbour));
+                } else {
+                    evaluations.push(neighbour);
+                }
+            }
+            evaluations.push(current);
+        },
+    }
+
+    let mut best : Option<Evaluation> = None;
+    for evaluation in evaluations {
+        if evaluation.feasible && best.as_ref().is_none_or(|b| evaluation.mean < b.mean) {
+            best = Some(evaluation);
+        }
+    }
+    return best;
+}
+
+fn replication_study(options : &SimOptions, first_seed : u64, replications : usize, antithetic : bool, control_variate : bool) {
+    // Estimates the average wait over several days, optionally with antithetic pairs of days and the number of customers
+    // generated as a control variate, and reports how much each cut the variance of the estimate
+    let mut waits = Vec::new();
+    let mut generated = Vec::new();
+    let pairs = replications.div_ceil(2);
+    let runs = if antithetic {pairs * 2} else {replications};
+    for run in 0..runs {
+        let mut run_options = options.clone();
+        let seed = if antithetic {first_seed + (run / 2) as u64} else {first_seed + run as u64};
+        if antithetic {
+            run_options.arrival_draws = if run % 2 == 0 {ArrivalDraws::Inverse()} else {ArrivalDraws::Mirrored()};
+        }
+        let sim = run_simulation(build_simulation(&run_options, seed));
+        waits.push(sim.bookkeeping.total_station_waiting_time / sim.bookkeeping.total_customers_boarded);
+        generated.push(sim.bookkeeping.total_customers);
+    }
+    let expected = build_simulation(options, first_seed).expected_customers();
+    let variance = |samples : &[f32]| {
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        samples.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / (samples.len() as f32 - 1.0).max(1.0)
+    };
+
+    println!("Replication Study:");
+    print!("    DAYS SIMULATED: {}{}\n", runs, if antithetic {format!(" ({} ANTITHETIC PAIRS)", pairs)} else {String::new()});
+    let (mean, half_width) = mean_and_half_width(&waits);
+    let plain_variance = variance(&waits) / runs as f32; // What the estimate's variance would be if every day was independent
+    print!("    AVERAGE WAIT (INDEPENDENT DAYS): {:.4} +/- {:.4}\n", mean, half_width);
+
+    // Antithetic pairs are averaged first, the pairs are independent of each other
+    let (units, unit_generated) : (Vec<f32>, Vec<f32>) = if antithetic {
+        ((0..pairs).map(|pair| (waits[2 * pair] + waits[2 * pair + 1]) / 2.0).collect(),
+         (0..pairs).map(|pair| (generated[2 * pair] + generated[2 * pair + 1]) / 2.0).collect())
+    } else {
+        (waits.clone(), generated.clone())
+    };
+    let unit_variance = variance(&units) / units.len() as f32;
+    if antithetic {
+        let (mean, half_width) = mean_and_half_width(&units);
+        print!("    AVERAGE WAIT (ANTITHETIC): {:.4} +/- {:.4}\n", mean, half_width);
+        print!("    VARIANCE REDUCTION FROM ANTITHETIC DAYS: {:.2}%\n", (1.0 - unit_variance / plain_variance) * 100.0);
+    }
+
+    if control_variate {
+        let unit_mean = units.iter().sum::<f32>() / units.len() as f32;
+        let generated_mean = unit_generated.iter().sum::<f32>() / units.len() as f32;
+        let mut covariance = 0.0;
+        for i in 0..units.len() {
+            covariance += (units[i] - unit_mean) * (unit_generated[i] - generated_mean);
+        }
+        covariance /= (units.len() as f32 - 1.0).max(1.0);
+        let generated_variance = variance(&unit_generated);
+        let beta = if generated_variance > 0.0 {covariance / generated_variance} else {0.0};
+        let adjusted: Vec<f32> = (0..units.len()).map(|i| units[i] - beta * (unit_generated[i] - expected)).collect();
+        let (mean, half_width) = mean_and_half_width(&adjusted);
+        let adjusted_variance = variance(&adjusted) / units.len() as f32;
+        print!("    CUSTOMERS GENERATED (AVERAGE / EXPECTED): {:.1} / {:.1}\n", generated_mean, expected);
+        print!("    AVERAGE WAIT (CONTROL VARIATE, BETA = {:.6}): {:.4} +/- {:.4}\n", beta, mean, half_width);
+        print!("    VARIANCE REDUCTION FROM THE CONTROL VARIATE: {:.2}%\n", (1.0 - adjusted_variance / unit_variance) * 100.0);
+        if antithetic {
+            print!("    TOTAL VARIANCE REDUCTION: {:.2}%\n", (1.0 - adjusted_variance / plain_variance) * 100.0);
+        }
+    }
+}
+
+struct SelectionSettings { // How sure we need to be when picking the best of several dispatch candidates
+    first_stage: usize, // Replications every candidate gets before any are dropped
+    max_replications: usize, // Give up and pick the lowest mean once the survivors have had this many
+    confidence: f32, // Chance of picking the best candidate (or one within the indifference zone of it)
+    indifference: Option<f32>, // Smallest difference in the objective worth telling apart, None to use 0.5% of the first stage mean
+    objective: Objective,
+    costs: CostModel,
+}
+
+fn read_candidates(path : &str) -> Result<Vec<(String, DispatchTypes)>, String> {
+    // Reads the dispatch candidates to pick between, one per line: <name>,<constant|timebased|popbased|translink>[,<parameter>]
+    // Blank lines and lines starting with # are ignored
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read candidate file {}: {}", path, e))?;
+    let mut candidates = Vec::new();
+
+    for (line_number, text) in contents.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') { continue; }
+
+        let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 2 && fields.len() != 3 { return Err(format!("Candidate file line {} is invalid", line_number + 1)); }
+        let parameter = match fields.get(2) {
+            Some(parameter) => Some(parameter.parse::<f32>().map_err(|_| format!("Candidate file line {} has an invalid parameter", line_number + 1))?),
+            None => None,
+        };
+        let dispatch_type = DispatchTypes::from_name(fields[1], parameter)
+            .ok_or(format!("Candidate file line {} has an unknown dispatch method: {}", line_number + 1, fields[1]))?;
+        candidates.push((String::from(fields[0]), dispatch_type));
+    }
+
+    if candidates.len() < 2 {
+        return Err(format!("Candidate file {} needs at least two candidates", path));
+    }
+    return Ok(candidates);
+}
+
+fn kim_nelson_h_squared(confidence : f32, candidates : usize, first_stage : usize) -> f32 {
+    // Kim and Nelson's h^2, how far apart two candidates' means can drift before one of them is dropped
+    let alpha = 1.0 - confidence;
+    let eta = 0.5 * ((2.0 * alpha / (candidates - 1) as f32).powf(-2.0 / (first_stage - 1) as f32) - 1.0);
+    return 2.0 * eta * (first_stage - 1) as f32;
+}
+
+fn kim_nelson_screen(means : &[f32], pair_variance : &[f32], surviving : &[bool], r : usize, delta : f32, h_squared : f32) -> Vec<usize> {
+    // Returns the surviving candidates whose mean after r replications is clearly worse than another surviving candidate's
+    // The allowance shrinks as replications are added, so candidates that stay close are kept until the indifference zone decides it
+    let k = means.len();
+    let mut dropped = Vec::new();
+    for i in 0..k {
+        if !surviving[i] { continue; }
+        for l in 0..k {
+            if i == l || !surviving[l] { continue; }
+            let allowance = (delta / (2.0 * r as f32) * (h_squared * pair_variance[i * k + l] / (delta * delta) - r as f32)).max(0.0);
+            if means[i] > means[l] + allowance {
+                dropped.push(i);
+                break;
+            }
+        }
+    }
+    return dropped;
+}
+
+fn select_best(options : &SimOptions, first_seed : u64, candidates : &[(String, DispatchTypes)], settings : &SelectionSettings) {
+    // Picks the candidate with the lowest objective using Kim and Nelson's fully sequential procedure
+    // Every candidate runs the same seeds (CRN), and candidates are dropped as soon as they are clearly worse than another one
+    let k = candidates.len();
+    let n0 = settings.first_stage.max(2);
+    let candidate_options: Vec<SimOptions> = candidates.iter().map(|(_, dispatch_type)| {
+        let mut candidate = options.clone();
+        candidate.dispatch_type = dispatch_type.clone();
+        candidate
+    }).collect();
+    let run = |candidate : usize, replication : usize| -> f32 {
+        let sim = run_simulation(build_simulation(&candidate_options[candidate], first_seed + replication as u64));
+        return settings.objective.value(&sim.bookkeeping, &settings.costs);
+    };
+
+    // First stage
+    let mut samples: Vec<Vec<f32>> = (0..k).map(|candidate| (0..n0).map(|replication| run(candidate, replication)).collect()).collect();
+    let first_means: Vec<f32> = samples.iter().map(|values| values.iter().sum::<f32>() / n0 as f32).collect();
+    let delta = settings.indifference.unwrap_or(0.005 * first_means.iter().sum::<f32>().abs() / k as f32).max(f32::EPSILON);
+
+    // Variance of the difference between each pair over the first stage
+    let mut pair_variance = vec![0.0; k * k];
+    for i in 0..k {
+        for l in 0..k {
+            if i == l { continue; }
+            let mean_difference = first_means[i] - first_means[l];
+            pair_variance[i * k + l] = (0..n0).map(|j| (samples[i][j] - samples[l][j] - mean_difference).powi(2)).sum::<f32>() / (n0 - 1) as f32;
+        }
+    }
+    let h_squared = kim_nelson_h_squared(settings.confidence, k, n0);
+
+    let mut surviving = vec![true; k];
+    let mut dropped_at = vec![0; k];
+    let mut r = n0;
+    loop {
+        let means: Vec<f32> = samples.iter().map(|values| values.iter().sum::<f32>() / values.len() as f32).collect();
+        for i in kim_nelson_screen(&means, &pair_variance, &surviving, r, delta, h_squared) {
+            surviving[i] = false;
+            dropped_at[i] = r;
+        }
+
+        if surviving.iter().filter(|&&alive| alive).count() == 1 || r >= settings.max_replications { break; }
+        for candidate in 0..k {
+            if surviving[candidate] { samples[candidate].push(run(candidate, r)); }
+        }
+        r += 1;
+    }
+
+    // Lowest mean of whatever is left (only more than one if we hit the replication limit)
+    let means: Vec<f32> = samples.iter().map(|values| values.iter().sum::<f32>() / values.len() as f32).collect();
+    let mut best = usize::MAX;
+    for candidate in 0..k {
+        if surviving[candidate] && (best == usize::MAX || means[candidate] < means[best]) { best = candidate; }
+    }
+
+    println!("Ranking and selection (Kim-Nelson) between {} candidates, {:.0}% chance of correct selection, indifference zone {:.3}\n",
+        k, settings.confidence * 100.0, delta);
+    for candidate in 0..k {
+        let (mean, half_width) = mean_and_half_width(&samples[candidate]);
+        let status = if candidate == best {String::from("SELECTED")} else if surviving[candidate] {String::from("STILL IN CONTENTION")}
+            else {format!("DROPPED AFTER {} REPLICATIONS", dropped_at[candidate])};
+        print!("    {}: {:.2} +/- {:.2} OVER {} REPLICATIONS, {}\n", candidates[candidate].0, mean, half_width, samples[candidate].len(), status);
+    }
+    let total : usize = samples.iter().map(|values| values.len()).sum();
+    println!("\nBest Candidate:");
+    print!("    {}\n", candidates[best].0);
+    print!("    TOTAL REPLICATIONS RUN: {}\n", total);
+    if surviving.iter().filter(|&&alive| alive).count() > 1 {
+        print!("    NOTE: STOPPED AT {} REPLICATIONS BEFORE EVERY OTHER CANDIDATE WAS RULED OUT\n", settings.max_replications);
+    }
+}
+
+fn main() {
+    // Main simulation loop
+
+
+    // Get command line arguements
+    let mut args: Vec<String> = env::args().collect();
+
+    // Looking through a trace from an earlier run instead of simulating
+    if args.get(1).is_some_and(|command| command == "inspect") {
+        if let Err(message) = inspect_trace(args) {
+            println!("ERROR: {}", message);
+            println!("USAGE: inspect <trace file> [--train <id>] [--station <name>] [--from <time>] [--to <time>] [--at <time>]");
+        }
+        return
+    }
 
+    // Optional arguements come first so the positional ones are left in place
+    let fleet_path = take_option(&mut args, "--fleet");
+    let default_trains : usize = match take_option(&mut args, "--trains").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(trains)) if trains > 0 && trains <= usize::from(u8::MAX) + 1 => trains,
+        Some(_) => {
+            println!("ERROR: --trains must be between 1 and {}", usize::from(u8::MAX) + 1);
+            return
+        },
+        None => usize::from(NUMBER_OF_TRAINS),
+    };
+    let costs = match take_option(&mut args, "--costs") {
+        Some(path) => match CostModel::from_file(&path) {
+            Ok(costs) => costs,
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        },
+        None => CostModel::default_costs(),
+    };
+    let balk_queue_length : Option<usize> = match take_option(&mut args, "--balk-at").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(customers)) => Some(customers),
+        Some(Err(_)) => {
+            println!("ERROR: --balk-at needs a whole number of customers");
+            return
+        },
+        None => None,
+    };
+    let mean_patience : Option<f32> = match take_option(&mut args, "--patience").map(|n| n.trim().parse::<f32>()) {
+        Some(Ok(minutes)) if minutes > 0.0 => Some(minutes),
+        Some(_) => {
+            println!("ERROR: --patience needs a number of minutes above 0");
+            return
+        },
+        None => None,
+    };
+    let platform_capacity : Option<usize> = match take_option(&mut args, "--platform-capacity").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(capacity)) if capacity > 0 => Some(capacity),
+        Some(_) => {
+            println!("ERROR: --platform-capacity needs a whole number of customers greater than 0");
+            return
+        },
+        None => None,
+    };
+    let meter_rate : usize = match take_option(&mut args, "--meter-rate").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(rate)) if rate > 0 => rate,
+        Some(_) => {
+            println!("ERROR: --meter-rate needs a whole number of customers per minute greater than 0");
+            return
+        },
+        None => 60,
+    };
+    let logit_scale : f32 = match take_option(&mut args, "--logit-scale").map(|n| n.trim().parse::<f32>()) {
+        Some(Ok(scale)) if scale >= 0.0 => scale,
+        Some(_) => {
+            println!("ERROR: --logit-scale needs a number of 0 or more");
+            return
+        },
+        None => 0.5,
+    };
+    let route_choice = match take_option(&mut args, "--route-choice").unwrap_or(String::from("shortest")).to_lowercase().as_str() {
+        "shortest" => RouteChoice::Shortest(),
+        "logit" => RouteChoice::Logit(logit_scale),
+        _ => {
+            println!("INVALID ROUTE CHOICE, USE ONE OF THE FOLLOWING: <shortest|logit>");
+            return
+        }
+    };
+    let network_name = take_option(&mut args, "--network");
+    let mut lines = match network_name.clone().unwrap_or(String::from("millennium")).to_lowercase().as_str() {
+        "millennium" => vec![millennium_line()],
+        "skytrain" => skytrain_lines(),
+        _ => {
+            println!("INVALID NETWORK, USE ONE OF THE FOLLOWING: <millennium|skytrain>");
+            return
+        }
+    };
+    let gtfs_route = take_option(&mut args, "--gtfs-route");
+    let gtfs_service = take_option(&mut args, "--gtfs-service");
+    if let Some(directory) = take_option(&mut args, "--gtfs") {
+        if network_name.is_some() {
+            println!("ERROR: --gtfs builds its own line and can't be used with --network");
+            return
+        }
+        let route = match gtfs_route {
+            Some(route) => route,
+            None => {
+                println!("ERROR: --gtfs needs the route to simulate, give it with --gtfs-route <route id or name>");
+                return
+            }
+        };
+        match read_gtfs_line(&directory, &route, gtfs_service.as_deref()) {
+            Ok(line) => lines = vec![line],
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        }
+    }
+    let timetable_path = take_option(&mut args, "--timetable");
+    if let Some(path) = &timetable_path {
+        for line in lines.iter_mut() {
+            line.timetable = [Vec::new(), Vec::new()]; // The file replaces any timetable that came with the GTFS feed
+        }
+        if let Err(message) = read_timetable(path, &mut lines) {
+            println!("ERROR: {}", message);
+            return
+        }
+    }
+    if let Some(path) = take_option(&mut args, "--gradients") {
+        if let Err(message) = read_gradients(&path, &mut lines) {
+            println!("ERROR: {}", message);
+            return
+        }
+    }
+    let search_steps : Option<usize> = match take_option(&mut args, "--steps").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(steps)) if steps > 0 => Some(steps),
+        Some(_) => {
+            println!("ERROR: --steps needs a whole number of 1 or more");
+            return
+        },
+        None => None,
+    };
+    let search_method = match take_option(&mut args, "--optimize").map(|method| method.to_lowercase()).as_deref() {
+        None => None,
+        Some("grid") => Some(SearchMethod::Grid(search_steps.unwrap_or(9))),
+        Some("anneal") => Some(SearchMethod::Anneal(search_steps.unwrap_or(20))),
+        Some(_) => {
+            println!("INVALID SEARCH METHOD, USE ONE OF THE FOLLOWING: <grid|anneal>");
+            return
+        }
+    };
+    let search_range : Option<(f32, f32)> = match take_option(&mut args, "--search") {
+        Some(range) => {
+            let bounds: Vec<Result<f32, _>> = range.split(',').map(|n| n.trim().parse::<f32>()).collect();
+            match bounds.as_slice() {
+                [Ok(low), Ok(high)] if low.is_finite() && high.is_finite() => Some((low.min(*high), low.max(*high))),
+                _ => {
+                    println!("ERROR: --search needs two numbers separated by a comma, e.g. --search 2,10");
+                    return
+                }
+            }
+        },
+        None => None,
+    };
+    let replications : Option<usize> = match take_option(&mut args, "--replications").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(count)) if count > 0 => Some(count),
+        Some(_) => {
+            println!("ERROR: --replications needs a whole number of 1 or more");
+            return
+        },
+        None => None,
+    };
+    let select_path = take_option(&mut args, "--select");
+    let replicate : Option<usize> = match take_option(&mut args, "--replicate").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(days)) if days >= 2 => Some(days),
+        Some(_) => {
+            println!("ERROR: --replicate needs a whole number of 2 or more days");
+            return
+        },
+        None => None,
+    };
+    let antithetic = take_flag(&mut args, "--antithetic");
+    let control_variate = take_flag(&mut args, "--control-variate");
+    if (antithetic || control_variate) && replicate.is_none() {
+        println!("ERROR: --antithetic and --control-variate only work with --replicate");
+        return
+    }
+    let max_replications : usize = match take_option(&mut args, "--max-replications").map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(count)) if count > 0 => count,
+        Some(_) => {
+            println!("ERROR: --max-replications needs a whole number of 1 or more");
+            return
+        },
+        None => 200,
+    };
+    let confidence : f32 = match take_option(&mut args, "--confidence").map(|n| n.trim().parse::<f32>()) {
+        Some(Ok(p)) if p > 0.0 && p < 1.0 => p,
+        Some(_) => {
+            println!("ERROR: --confidence needs a probability between 0 and 1, e.g. 0.95");
+            return
+        },
+        None => 0.95,
+    };
+    let indifference : Option<f32> = match take_option(&mut args, "--indifference").map(|n| n.trim().parse::<f32>()) {
+        Some(Ok(amount)) if amount > 0.0 => Some(amount),
+        Some(_) => {
+            println!("ERROR: --indifference needs an amount greater than 0");
+            return
+        },
+        None => None,
+    };
+    let max_trains : Option<f32> = match take_option(&mut args, "--max-trains").map(|n| n.trim().parse::<f32>()) {
+        Some(Ok(trains)) if trains > 0.0 => Some(trains),
+        Some(_) => {
+            println!("ERROR: --max-trains needs a number of trains greater than 0");
+            return
+        },
+        None => None,
+    };
+    let objective = match take_option(&mut args, "--objective").unwrap_or(String::from("cost")).to_lowercase().as_str() {
+        "cost" => Objective::SocialCost(),
+        "operating" => Objective::OperatingCost(),
+        "passenger" => Objective::PassengerCost(),
+        "wait" => Objective::AverageWait(),
+        "lost" => Objective::LostCustomers(),
+        _ => {
+            println!("INVALID OBJECTIVE, USE ONE OF THE FOLLOWING: <cost|operating|passenger|wait|lost>");
+            return
+        }
+    };
+    let failures_enabled = take_flag(&mut args, "--failures");
+    let disruptions_path = take_option(&mut args, "--disruptions");
+    let services_path = take_option(&mut args, "--services");
+    let od_report_path = take_option(&mut args, "--od-report");
+    let export_gtfs_path = take_option(&mut args, "--export-gtfs");
+    let export_timetable_path = take_option(&mut args, "--export-timetable");
+    let marey_path = take_option(&mut args, "--marey");
+    let html_report_path = take_option(&mut args, "--html-report");
+    let trace_path = take_option(&mut args, "--trace");
+    let log_spec = take_option(&mut args, "--log").or(env::var("TRANSIT_LOG").ok());
+    let mut log = match log_spec.as_deref().map(Logger::from_spec) {
+        Some(Ok(log)) => log,
+        Some(Err(message)) => {
+            println!("ERROR: {}", message);
+            return
+        },
+        None => Logger::off(),
+    };
+    if log.is_on() && (replicate.is_some() || search_method.is_some() || select_path.is_some()) {
+        // Those run many days, the messages would pile up from all of them
+        println!("ERROR: --log (or TRANSIT_LOG) only works when running a single day, not with --replicate, --optimize or --select");
+        return
+    }
+    log.trains = match take_option(&mut args, "--log-train").map(|trains| trains.split(',').map(|n| n.trim().parse::<usize>()).collect::<Result<Vec<usize>, _>>()) {
+        Some(Ok(trains)) => Some(trains),
+        Some(Err(_)) => {
+            println!("ERROR: --log-train needs train IDs separated by commas, e.g. --log-train 3,7");
+            return
+        },
+        None => None,
+    };
+    log.station = take_option(&mut args, "--log-station");
+    if let Some(window) = take_option(&mut args, "--log-window") {
+        match parse_time_window(&window) {
+            Some(window) => log.window = window,
+            None => {
+                println!("ERROR: --log-window takes two clock times, e.g. --log-window 07:00,09:00");
+                return
+            }
+        }
+    }
+    if let Some(path) = take_option(&mut args, "--log-file") {
+        match std::fs::File::create(&path) {
+            Ok(file) => log.file = Some(std::io::BufWriter::new(file)),
+            Err(e) => {
+                println!("ERROR: Could not create {}: {}", path, e);
+                return
+            }
+        }
+    }
+    let marey_window = match take_option(&mut args, "--marey-window").map(|window| parse_time_window(&window)) {
+        Some(Some(window)) => window,
+        Some(None) => {
+            println!("ERROR: --marey-window takes two clock times, e.g. --marey-window 07:00,09:00");
+            return
+        },
+        None => (0.0, SIMULATION_LENGTH),
+    };
+    let express_every : u32 = match take_option(&mut args, "--express-every").map(|n| n.trim().parse::<u32>()) {
+        Some(Ok(every)) if every > 0 => every,
+        Some(_) => {
+            println!("ERROR: --express-every needs a whole number of trains above 0");
+            return
+        },
+        None => 2,
+    };
+    let stopping_policy = match take_option(&mut args, "--stopping").unwrap_or(String::from("all")).to_lowercase().as_str() {
+        "all" => StoppingPolicy::AllStops(),
+        "skipstop" => StoppingPolicy::SkipStop(),
+        "express" => StoppingPolicy::Express(express_every),
+        _ => {
+            println!("INVALID STOPPING POLICY, USE ONE OF THE FOLLOWING: <all|skipstop|express>");
+            return
+        }
+    };
+    let consist_preference = match take_option(&mut args, "--prefer").unwrap_or(String::from("first")).to_lowercase().as_str() {
+        "first" => ConsistPreference::FirstAvailable(),
+        "largest" => ConsistPreference::Largest(),
+        "smallest" => ConsistPreference::Smallest(),
+        "peak" => ConsistPreference::PeakLargest(),
+        _ => {
+            println!("INVALID CONSIST PREFERENCE, USE ONE OF THE FOLLOWING: <first|largest|smallest|peak>");
+            return
+        }
+    };
+
+    let coupling_rule = if let Some(path) = take_option(&mut args, "--couple-schedule") {
+        match read_coupling_schedule(&path) {
+            Ok(windows) => CouplingRule::Schedule(windows),
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        }
+    } else if let Some(customers_per_car) = take_option(&mut args, "--couple-demand") {
+        match customers_per_car.trim().parse::<f32>() {
+            Ok(customers_per_car) if customers_per_car > 0.0 => CouplingRule::Demand(customers_per_car),
+            _ => {
+                println!("ERROR: --couple-demand needs a number of customers per car above 0, not {}", customers_per_car);
+                return
+            }
+        }
+    } else {
+        CouplingRule::Never()
+    };
+
+    if args.len() < 3 && !(args.len() == 2 && select_path.is_some()) {
+        println!("ERROR: Please provide the following arguements\n<seed> <constant|timebased|popbased|translink|timetable> <parameter> [--fleet <file>] [--prefer <first|largest|smallest|peak>]");
+        println!("[--couple-schedule <file> | --couple-demand <customers per car>] [--failures] [--disruptions <file>] [--services <file>]");
+        println!("[--stopping <all|skipstop|express>] [--express-every <n>] [--od-report <file>] [--network <millennium|skytrain>]");
+        println!("[--route-choice <shortest|logit>] [--logit-scale <scale>] [--balk-at <customers>] [--patience <minutes>]");
+        println!("[--platform-capacity <customers>] [--meter-rate <customers per minute>] [--gradients <file>]");
+        println!("[--trains <n>] [--costs <file>] [--optimize <grid|anneal> --search <low>,<high>] [--steps <n>] [--replications <n>]");
+        println!("[--objective <cost|operating|passenger|wait|lost>] [--max-trains <n>] [--select <file>] [--confidence <p>]");
+        println!("[--indifference <amount>] [--max-replications <n>] [--replicate <n>] [--antithetic] [--control-variate] [--timetable <file>]");
+        println!("[--gtfs <directory> --gtfs-route <route id or name>] [--gtfs-service <service id or weekday>]");
+        println!("[--export-gtfs <directory>] [--export-timetable <file>] [--marey <file>] [--marey-window <HH:MM>,<HH:MM>]");
+        println!("[--html-report <file>] [--trace <file>] [--log <category>=<level>,...] [--log-train <id>,...] [--log-station <name>]");
+        println!("[--log-window <HH:MM>,<HH:MM>] [--log-file <file>]");
+        println!("Or to look through a trace: inspect <trace file> [--train <id>] [--station <name>] [--from <time>] [--to <time>] [--at <time>]");
+        return
+    }
+
+    let seed : u64 = args[1].parse().unwrap();
+
+    let parameter : Option<f32> = args.get(3).map(|n| n.parse().unwrap());
+    let dispatch_type = match args.get(2) {
+        Some(name) => match DispatchTypes::from_name(name, parameter) {
+            Some(dispatch_type) => dispatch_type,
+            None => {
+                println!("INVALID DISPATCH METHOD, USE ONE OF THE FOLLOWING: <constant|timebased|popbased|translink|timetable>");
+                return
+            }
+        },
+        None => DispatchTypes::TransLink(), // Only when picking between candidates, which bring their own dispatch
+    };
+    if args.len() == 3 {
+        println!("Useing default parameter: {}", dispatch_type.parameter().unwrap_or(0.0));
+    }
+    if matches!(dispatch_type, DispatchTypes::Timetable()) && lines.iter().all(|line| line.timetable.iter().all(|departures| departures.is_empty())) {
+        println!("ERROR: Timetable dispatch needs a timetable, give one with --timetable <file> or load one with --gtfs");
+        return
+    }
+
+    let fleet = match fleet_path {
+        Some(path) => match Fleet::from_file(&path) {
+            Ok(fleet) => fleet,
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        },
+        None => Fleet::default_fleet(default_trains),
+    };
+
+    let disruptions = match disruptions_path {
+        Some(path) => match read_disruptions(&path, &lines) {
+            Ok(disruptions) => disruptions,
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let (turnbacks, service_patterns) = match services_path {
+        Some(path) => match read_services(&path, &lines) {
+            Ok(services) => services,
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        },
+        None => (Vec::new(), Vec::new()),
+    };
+
+    let options = SimOptions {lines : lines, route_choice : route_choice, balk_queue_length : balk_queue_length,
+        mean_patience : mean_patience, platform_capacity : platform_capacity, meter_rate : meter_rate, dispatch_type : dispatch_type, fleet : fleet, consist_preference : consist_preference,
+        coupling_rule : coupling_rule, failures_enabled : failures_enabled, disruptions : disruptions,
+        turnbacks : turnbacks, service_patterns : service_patterns, stopping_policy : stopping_policy, arrival_draws : ArrivalDraws::Ziggurat(),
+        log_movements : export_gtfs_path.is_some() || export_timetable_path.is_some() || marey_path.is_some()};
+
+    // Search for the best dispatch parameter instead of running a single day
+    if let Some(method) = search_method {
+        let range = match (search_range, options.dispatch_type.parameter()) {
+            (Some(range), Some(_)) => range,
+            (None, _) => {
+                println!("ERROR: --optimize needs a parameter range to search, e.g. --search 2,10");
+                return
+            },
+            (_, None) => {
+                println!("ERROR: TransLink's dispatch has no parameter to optimize");
+                return
+            },
+        };
+        let replications = replications.unwrap_or(5);
+        let settings = SearchSettings {method : method, range : range, replications : replications, objective : objective,
+            costs : costs, max_trains : max_trains};
+        println!("Searching {} from {} to {} with {} replications per configuration (seeds {} to {})\n", options.dispatch_type.parameter_name(),
+            range.0, range.1, replications, seed, seed + replications as u64 - 1);
+        match optimize_dispatch(&options, seed, &settings) {
+            Some(best) => {
+                println!("\nBest Configuration:");
+                print!("    {} = {:.3}\n", options.dispatch_type.parameter_name(), best.parameter);
+                print!("    OBJECTIVE: {:.2} (95% CI {:.2} TO {:.2})\n", best.mean, best.mean - best.half_width, best.mean + best.half_width);
+                print!("    PEAK TRAINS DEPLOYED: {:.1}\n", best.peak_trains);
+            },
+            None => println!("\nNO CONFIGURATION STAYED WITHIN THE FLEET LIMIT"),
+        }
+        return
+    }
+
+    // Estimate the average wait over several days instead of running a single day
+    if let Some(days) = replicate {
+        replication_study(&options, seed, days, antithetic, control_variate);
+        return
+    }
+
+    // Pick the best of several dispatch candidates instead of running a single day
+    if let Some(path) = select_path {
+        let candidates = match read_candidates(&path) {
+            Ok(candidates) => candidates,
+            Err(message) => {
+                println!("ERROR: {}", message);
+                return
+            }
+        };
+        let settings = SelectionSettings {first_stage : replications.unwrap_or(10), max_replications : max_replications,
+            confidence : confidence, indifference : indifference, objective : objective, costs : costs};
+        select_best(&options, seed, &candidates, &settings);
+        return
+    }
+
+    let mut sim = build_simulation(&options, seed);
+    sim.log = log;
+    if let Some(path) = &trace_path {
+        match std::fs::File::create(path) {
+            Ok(file) => sim.trace = Some(std::io::BufWriter::new(file)),
+            Err(e) => {
+                println!("ERROR: Could not create {}: {}", path, e);
+                return
+            }
+        }
+    }
+
+    // START SIMULATION TIMER
+    let timer = time::Instant::now();
+
+    let mut sim = run_simulation(sim);
+    if let Some(mut trace) = sim.trace.take() {
+        if let Err(e) = trace.flush() { println!("ERROR: Could not write the event trace: {}", e); }
+    }
+    if let Some(mut file) = sim.log.file.take() {
+        if let Err(e) = file.flush() { println!("ERROR: Could not write the log: {}", e); }
+    }
+
+    // Stop timer
+    let sim_realtime = timer.elapsed();
+    println!("-- Time to execute: {:.2?} --", sim_realtime);
+
+    // Generate and print report
     let title_string : String;
     
     title_string = match sim.dispatch_type {
@@ -787,13 +4991,315 @@ fn main() {
         DispatchTypes::TransLink() => format!("Results of simulation using TransLink's dispatch system (SEED = {})", seed).to_string(),
         DispatchTypes::TimeBased(offset) => format!("Results of simulation using a time-based dispatch system with an offset of {} (SEED = {})", offset, seed).to_string(),
         DispatchTypes::PopBased(multi) => format!("Results of simulation using a population-based dispatch system (m = {}) (SEED = {})", multi, seed).to_string(),
-        _ => "Simulation ran with unimplemented dispatch type... You shouldn't be seeing this message!".to_string(),
+        DispatchTypes::Timetable() => format!("Results of simulation using a timetable (SEED = {})", seed).to_string(),
     };
 
     // Prints the report
-    sim.bookkeeping.generate_report(title_string);
+    sim.bookkeeping.generate_report(title_string.clone(), &sim.network, &costs);
+    if let Some(path) = &html_report_path {
+        match sim.bookkeeping.write_html_report(path, &title_string, &sim.network, &sim.lines, &costs) {
+            Ok(()) => println!("\nHTML report written to {}", path),
+            Err(message) => println!("ERROR: {}", message),
+        }
+    }
 
-    // Empty FEL
-    sim.future_event_list.clear();
+    // Write out the trains the day actually ran
+    if let Some(directory) = &export_gtfs_path {
+        match sim.export_gtfs(directory) {
+            Ok(()) => println!("\nTrain movements written as GTFS to {}", directory),
+            Err(message) => println!("ERROR: {}", message),
+        }
+    }
+    if let Some(path) = &export_timetable_path {
+        match sim.export_timetable(path) {
+            Ok(()) => println!("\nTimetable written to {}", path),
+            Err(message) => println!("ERROR: {}", message),
+        }
+    }
+    if let Some(path) = &marey_path {
+        match sim.write_marey_diagram(path, marey_window) {
+            Ok(()) => println!("\nTime-distance diagram written to {}", path),
+            Err(message) => println!("ERROR: {}", message),
+        }
+    }
+
+    // Rerun the same day without the disruptions to see what they cost
+    if !options.disruptions.is_empty() {
+        let mut baseline_options = options.clone();
+        baseline_options.disruptions.clear();
+        let baseline = run_simulation(build_simulation(&baseline_options, seed));
+        sim.bookkeeping.print_disruption_impact(&baseline.bookkeeping);
+    }
+
+    // Rerun the same day with every train stopping everywhere to see who gains and who loses
+    if !matches!(options.stopping_policy, StoppingPolicy::AllStops()) {
+        let mut baseline_options = options.clone();
+        baseline_options.stopping_policy = StoppingPolicy::AllStops();
+        let baseline = run_simulation(build_simulation(&baseline_options, seed));
+        sim.bookkeeping.print_stopping_pattern_impact(&baseline.bookkeeping, &sim.network, od_report_path.as_ref());
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_options(lines : Vec<Line>, disruptions : Vec<Disruption>) -> SimOptions {
+        // A plain day on the given lines, every train stopping everywhere
+        return SimOptions {lines : lines, route_choice : RouteChoice::Shortest(), balk_queue_length : None, mean_patience : None,
+            platform_capacity : None, meter_rate : 0, arrival_draws : ArrivalDraws::Ziggurat(), log_movements : false,
+            dispatch_type : DispatchTypes::Constant(5.0), fleet : Fleet::default_fleet(10), consist_preference : ConsistPreference::FirstAvailable(),
+            coupling_rule : CouplingRule::Never(), failures_enabled : false, disruptions : disruptions, turnbacks : Vec::new(),
+            service_patterns : Vec::new(), stopping_policy : StoppingPolicy::AllStops()};
+    }
+
+    fn waiting_at(sim : &Simulation, line_id : usize, station_id : usize) -> usize {
+        let station = &sim.lines[line_id].stations[station_id];
+        return station.east_customers.len() + station.west_customers.len() + station.concourse.len();
+    }
+
+    fn rider(lines : &[Line], itinerary : Vec<Leg>) -> Customer {
+        // A customer following the given legs, their trip runs from the first leg's start to the last leg's end
+        let first = &itinerary[0];
+        let last = &itinerary[itinerary.len() - 1];
+        let origin = lines[first.line].network_ids[first.from];
+        let destination = lines[last.line].network_ids[last.to];
+        return Customer::new(0.0, itinerary, origin, destination);
+    }
 
+    #[test]
+    fn single_track_directions_take_turns() {
+        let mut section = SingleTrackSection {first_station : 3, last_station : 6, from : 60.0, to : 120.0,
+            direction : EASTWARD, trains_inside : Vec::new(), waiting : VecDeque::new()};
+        assert!(section.entered_by(EASTWARD, 3, 60.0));
+        assert!(section.entered_by(WESTWARD, 6, 90.0));
+        assert!(!section.entered_by(EASTWARD, 6, 90.0));
+        assert!(!section.entered_by(EASTWARD, 3, 120.0));
+        assert!(section.exited_by(EASTWARD, 6) && section.exited_by(WESTWARD, 3));
+
+        // An empty section can be entered from either end
+        assert!(section.can_enter(EASTWARD) && section.can_enter(WESTWARD));
+
+        // Trains can follow each other through, but not meet head on
+        section.trains_inside.push(0);
+        assert!(section.can_enter(EASTWARD));
+        assert!(!section.can_enter(WESTWARD));
+
+        // Once the other direction is waiting, the next train going the same way has to wait its turn
+        section.waiting.push_back((1, 5));
+        assert!(!section.can_enter(EASTWARD));
+    }
+
+    #[test]
+    fn diverted_customers_go_to_the_next_station_or_are_lost() {
+        let lines = vec![millennium_line()];
+        let mut sim = build_simulation(&test_options(lines, vec![Disruption::StationClosure(0, 5, 0.0, 600.0)]), 1);
+        sim.time_elapsed = 100.0;
+
+        let customers = 200;
+        for _ in 0..customers {
+            let customer = rider(&sim.lines, vec![Leg {line : 0, from : 5, to : 10}]);
+            sim.divert_customer(customer, false);
+        }
+        let diverted = sim.bookkeeping.customers_diverted;
+        let lost = sim.bookkeeping.customers_lost_to_closures;
+        assert_eq!(diverted + lost, customers as f32);
+        assert!(diverted > 0.0 && lost > 0.0);
+        assert_eq!(waiting_at(&sim, 0, 6), diverted as usize);
+        assert_eq!(sim.lines[0].stations[6].east_customers.iter().filter(|c| c.start_at == 6).count(), diverted as usize);
+        assert_eq!(sim.bookkeeping.currently_waiting_customers, diverted);
+    }
+
+    #[test]
+    fn customers_are_not_diverted_to_their_destination_or_a_closed_station() {
+        let lines = vec![millennium_line()];
+        let disruptions = vec![Disruption::StationClosure(0, 5, 0.0, 600.0), Disruption::StationClosure(0, 4, 0.0, 600.0)];
+        let mut sim = build_simulation(&test_options(lines, disruptions), 1);
+        sim.time_elapsed = 100.0;
+
+        for _ in 0..50 {
+            let next_stop = rider(&sim.lines, vec![Leg {line : 0, from : 5, to : 6}]);
+            sim.divert_customer(next_stop, false);
+            let towards_closed = rider(&sim.lines, vec![Leg {line : 0, from : 5, to : 0}]);
+            sim.divert_customer(towards_closed, false);
+        }
+        assert_eq!(sim.bookkeeping.customers_diverted, 0.0);
+        assert_eq!(sim.bookkeeping.customers_lost_to_closures, 100.0);
+        assert_eq!(waiting_at(&sim, 0, 6) + waiting_at(&sim, 0, 4), 0);
+    }
+
+    #[test]
+    fn early_alight_at_a_closed_transfer_keeps_the_trip_going() {
+        // Riding the Expo Line to change to the Millennium Line at Commercial–Broadway, which is closed on both lines
+        let lines = skytrain_lines();
+        let transfer = (lines[0].name_to_id("Commercial–Broadway").unwrap(), lines[1].name_to_id("Commercial–Broadway").unwrap());
+        let disruptions = vec![Disruption::StationClosure(0, transfer.0, 0.0, 600.0), Disruption::StationClosure(1, transfer.1, 0.0, 600.0)];
+        let mut sim = build_simulation(&test_options(lines, disruptions), 1);
+        sim.time_elapsed = 100.0;
+
+        let brentwood = sim.lines[0].name_to_id("Brentwood Town Centre").unwrap();
+        let customer = rider(&sim.lines, vec![Leg {line : 1, from : 0, to : transfer.1}, Leg {line : 0, from : transfer.0, to : brentwood}]);
+        sim.bookkeeping.total_customers_boarded += 1.0;
+        sim.customer_alights(customer, transfer.1 - 1); // Got off the stop before
+
+        assert_eq!(sim.bookkeeping.customers_rerouted, 1.0);
+        assert_eq!(sim.bookkeeping.customers_lost_to_closures, 0.0);
+        assert_eq!(sim.bookkeeping.total_customers_departed, 0.0);
+        assert_eq!(sim.bookkeeping.total_customers_boarded, 0.0);
+        assert_eq!(sim.bookkeeping.currently_waiting_customers, 1.0);
+
+        // They are waiting somewhere for their next train, with no change of trains left at the closed station
+        let mut found = None;
+        for line in &sim.lines {
+            for station in &line.stations {
+                for customer in station.east_customers.iter().chain(station.west_customers.iter()) {
+                    found = Some(customer.clone());
+                }
+            }
+        }
+        let customer = found.expect("rerouted customer is not waiting anywhere");
+        assert_eq!(customer.start_at, sim.lines[customer.line].name_to_id("Main Street–Science World").unwrap());
+        let mut legs = vec![Leg {line : customer.line, from : customer.start_at, to : customer.end_at}];
+        legs.extend(customer.itinerary.iter().cloned());
+        for leg in &legs {
+            assert!(!sim.lines[leg.line].is_closed(leg.from, 100.0) && !sim.lines[leg.line].is_closed(leg.to, 100.0));
+        }
+        let last = &legs[legs.len() - 1];
+        assert_eq!(sim.lines[last.line].network_ids[last.to], customer.destination);
+    }
+
+    #[test]
+    fn early_alight_without_a_way_around_is_lost() {
+        // Without the Production Way branch, Commercial–Broadway is the only place to change between the two lines
+        let mut lines = skytrain_lines();
+        lines.truncate(2);
+        let transfer = (lines[0].name_to_id("Commercial–Broadway").unwrap(), lines[1].name_to_id("Commercial–Broadway").unwrap());
+        let disruptions = vec![Disruption::StationClosure(0, transfer.0, 0.0, 600.0), Disruption::StationClosure(1, transfer.1, 0.0, 600.0)];
+        let mut sim = build_simulation(&test_options(lines, disruptions), 1);
+        sim.time_elapsed = 100.0;
+
+        let customer = rider(&sim.lines, vec![Leg {line : 1, from : 0, to : transfer.1}, Leg {line : 0, from : transfer.0, to : 0}]);
+        sim.bookkeeping.total_customers_boarded += 1.0;
+        sim.customer_alights(customer, transfer.1 - 1);
+
+        assert_eq!(sim.bookkeeping.customers_rerouted, 0.0);
+        assert_eq!(sim.bookkeeping.customers_lost_to_closures, 1.0);
+        assert_eq!(sim.bookkeeping.total_customers_departed, 0.0);
+        assert_eq!(sim.bookkeeping.currently_waiting_customers, 0.0);
+    }
+
+    #[test]
+    fn riding_costs_the_travel_time_and_the_dwell() {
+        let mut lines = vec![millennium_line()];
+        Network::link(&mut lines, RouteChoice::Shortest());
+        let graph = RouteGraph::new(&lines, &[2.0], &[1.5]);
+        for station_id in 0..lines[0].length() - 1 {
+            let expected = (lines[0].inter_station_traveltimes[station_id] + STATION_DWELL_TIME) * 1.5;
+            assert_eq!(graph.edge_cost(station_id, station_id + 1), expected);
+            assert_eq!(graph.edge_cost(station_id + 1, station_id), expected);
+        }
+
+        // A one-line trip is a single leg, costing the wait for the first train plus every segment ridden
+        let route = graph.path_to_route(&graph.cheapest_path(0, 3, &HashMap::new()).unwrap(), &lines);
+        assert_eq!(route.legs, vec![Leg {line : 0, from : 0, to : 3}]);
+        let riding : f32 = (0..3).map(|station_id| graph.edge_cost(station_id, station_id + 1)).sum();
+        assert!((route.cost - (2.0 + riding)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn routes_change_lines_where_they_meet() {
+        let mut lines = skytrain_lines();
+        let mut network = Network::link(&mut lines, RouteChoice::Shortest());
+        network.plan_trips(&lines, &vec![3.0; lines.len()], &vec![1.0; lines.len()]);
+        let waterfront = network.name_to_id("Waterfront").unwrap();
+        let brentwood = network.name_to_id("Brentwood Town Centre").unwrap();
+        let transfer = network.name_to_id("Commercial–Broadway").unwrap();
+
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+        let (legs, cost, cheapest) = network.plan_trip(waterfront, brentwood, &mut rng);
+        assert!(cheapest);
+        assert_eq!(legs.len(), 2);
+        assert_eq!(lines[legs[0].line].network_ids[legs[0].to], transfer);
+        assert_eq!(legs[1].line, 0);
+        assert_eq!(lines[0].network_ids[legs[1].from], transfer);
+        assert_eq!(lines[0].network_ids[legs[1].to], brentwood);
+        assert!(cost > 3.0 + TRANSFER_WALK_TIME + TRANSFER_PENALTY + 3.0);
+
+        // Both ways round are planned, and a trip on one line never changes trains
+        assert!(!network.routes[brentwood * network.length() + waterfront].is_empty());
+        let (legs, _, _) = network.plan_trip(brentwood, transfer, &mut rng);
+        assert_eq!(legs.len(), 1);
+    }
+
+    #[test]
+    fn routes_avoid_changing_trains_at_closed_stations() {
+        let mut lines = skytrain_lines();
+        let transfer = lines[0].name_to_id("Commercial–Broadway").unwrap();
+        lines[0].closures.push((transfer, 60.0, 120.0));
+        let mut network = Network::link(&mut lines, RouteChoice::Shortest());
+        network.plan_trips(&lines, &vec![3.0; lines.len()], &vec![1.0; lines.len()]);
+        let waterfront = network.name_to_id("Waterfront").unwrap();
+        let brentwood = network.name_to_id("Brentwood Town Centre").unwrap();
+
+        // Before the closure the usual change at Commercial–Broadway is fine
+        let open = network.route_avoiding(&lines, waterfront, brentwood, 30.0).unwrap();
+        assert_eq!(open.legs, network.routes[waterfront * network.length() + brentwood][0].legs);
+
+        // During it they go round and change where the Production Way branch meets the line, which costs more
+        let closed = network.route_avoiding(&lines, waterfront, brentwood, 90.0).unwrap();
+        assert!(closed.cost > open.cost);
+        for leg in &closed.legs {
+            assert!(!lines[leg.line].is_closed(leg.from, 90.0) && !lines[leg.line].is_closed(leg.to, 90.0));
+        }
+        let last = &closed.legs[closed.legs.len() - 1];
+        assert_eq!(lines[last.line].network_ids[last.to], brentwood);
+        let change = closed.legs.iter().find(|leg| leg.line == 0).expect("never boards the Millennium Line");
+        assert!(["Lougheed Town Centre", "Production Way–University"].contains(&lines[0].id_to_name(change.from).as_str()));
+
+        // The station is only closed on the Millennium Line, so it can still be reached on the Expo Line
+        let to_transfer = network.route_avoiding(&lines, waterfront, network.name_to_id("Commercial–Broadway").unwrap(), 90.0).unwrap();
+        assert!(to_transfer.legs.iter().all(|leg| leg.line != 0));
+    }
+
+    #[test]
+    fn kim_nelson_constant_matches_the_procedure() {
+        // Two candidates, 10 first stage replications, 95% confidence: eta = ((2 * 0.05)^(-2/9) - 1) / 2
+        let eta : f32 = 0.5 * (0.1_f32.powf(-2.0 / 9.0) - 1.0);
+        assert!((kim_nelson_h_squared(0.95, 2, 10) - 18.0 * eta).abs() < 1e-4);
+        // Asking for more confidence or comparing more candidates needs more evidence before dropping one
+        assert!(kim_nelson_h_squared(0.99, 2, 10) > kim_nelson_h_squared(0.95, 2, 10));
+        assert!(kim_nelson_h_squared(0.95, 5, 10) > kim_nelson_h_squared(0.95, 2, 10));
+    }
+
+    #[test]
+    fn kim_nelson_drops_clearly_worse_candidates() {
+        let h_squared = kim_nelson_h_squared(0.95, 3, 10);
+        let variance = vec![0.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0, 0.0];
+        let surviving = vec![true; 3];
+
+        // The third candidate is far behind the first, the second is within the allowance
+        let dropped = kim_nelson_screen(&[10.0, 10.5, 30.0], &variance, &surviving, 10, 0.5, h_squared);
+        assert_eq!(dropped, vec![2]);
+
+        // The allowance shrinks with more replications, until only the indifference zone is left
+        let dropped = kim_nelson_screen(&[10.0, 10.5, 30.0], &variance, &surviving, 1000, 0.5, h_squared);
+        assert_eq!(dropped, vec![1, 2]);
+
+        // The best candidate is never dropped, and neither are candidates with the same mean
+        let dropped = kim_nelson_screen(&[10.0, 10.0, 10.0], &variance, &surviving, 1000, 0.5, h_squared);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn kim_nelson_only_compares_surviving_candidates() {
+        let h_squared = kim_nelson_h_squared(0.95, 3, 10);
+        let variance = vec![0.0; 9];
+
+        // With no noise any worse mean is dropped, but only against candidates still in contention
+        let dropped = kim_nelson_screen(&[5.0, 10.0, 20.0], &variance, &[false, true, true], 10, 0.5, h_squared);
+        assert_eq!(dropped, vec![2]);
+        let dropped = kim_nelson_screen(&[5.0, 10.0, 20.0], &variance, &[false, true, false], 10, 0.5, h_squared);
+        assert!(dropped.is_empty());
+    }
 }